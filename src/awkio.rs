@@ -1,138 +1,411 @@
 use io::Result;
-use regex::Regex;
+use std::clone::Clone;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::fmt;
-use std::cmp::PartialEq;
-use std::clone::Clone;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use crate::value::split_fields;
 
 pub struct AwkIO {
-    inputs: HashMap<String, Option<Box<dyn BufRead>>>,
+    // The main input and standard output are kept apart from the files and
+    // commands a program names, so `getline < FILENAME` reads through a
+    // handle of its own and `print > "STDOUT"` writes to a file.
+    main_input: Option<Input>,
+    inputs: HashMap<String, Input>,
     outputs: HashMap<String, Box<dyn Write>>,
     fields: Vec<String>,
+    fields_split: bool,
+    field_separator: String,
+    output_separator: String,
+    record_dirty: bool,
     line: String,
+    #[cfg(test)]
+    splits: usize,
+    #[cfg(test)]
+    rebuilds: usize,
+}
+
+// Standard input is read through the process-wide handle, whether it is the
+// main input or `getline < "-"`.
+enum Input {
+    Stdin,
+    Reader(Box<dyn BufRead>),
+}
+
+impl Input {
+    fn open(file_path: &str) -> Result<Self> {
+        if file_path == "-" {
+            Ok(Input::Stdin)
+        } else if file_path.ends_with(".gz") {
+            Ok(Input::Reader(Box::new(BufReader::new(GzipInput::open(file_path)?))))
+        } else {
+            Ok(Input::Reader(Box::new(BufReader::new(File::open(file_path)?))))
+        }
+    }
+
+    fn read_line(&mut self, buffer: &mut String) -> Result<usize> {
+        match self {
+            Input::Stdin => io::stdin().read_line(buffer),
+            Input::Reader(reader) => reader.read_line(buffer),
+        }
+    }
+}
+
+// Reads a gzip-compressed file through a `gzip -dc` child process, so
+// records are read from the decompressed text. It needs a `gzip` binary on
+// the PATH; without one, opening the file fails.
+struct GzipInput {
+    child: Child,
+    output: ChildStdout,
+}
+
+impl GzipInput {
+    fn open(file_path: &str) -> Result<Self> {
+        // Open the file first so a missing file reports the usual error.
+        let handle = File::open(file_path)?;
+        let mut child =
+            Command::new("gzip").arg("-dc").stdin(handle).stdout(Stdio::piped()).spawn()?;
+        let output = child.stdout.take().unwrap();
+        Ok(Self { child, output })
+    }
+}
+
+impl Read for GzipInput {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        self.output.read(buffer)
+    }
+}
+
+impl Drop for GzipInput {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Reads the output of a command started by `"cmd" | getline`. Dropping it
+// closes the pipe and waits for the command to finish.
+struct CommandInput {
+    child: Child,
+    output: Option<ChildStdout>,
+    produced_output: bool,
+}
+
+impl CommandInput {
+    fn open(command_line: &str) -> Result<Self> {
+        let mut child =
+            Command::new("sh").arg("-c").arg(command_line).stdout(Stdio::piped()).spawn()?;
+        let output = child.stdout.take();
+        Ok(Self { child, output, produced_output: false })
+    }
+}
+
+impl Read for CommandInput {
+    // The shell always starts, so a command that could not be run only shows
+    // as exit status 126 or 127 once its output ends without any lines.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let read = match self.output.as_mut() {
+            Some(output) => output.read(buffer)?,
+            None => return Ok(0),
+        };
+
+        if read > 0 {
+            self.produced_output = true;
+        } else if !self.produced_output {
+            self.output.take();
+            if let Some(126 | 127) = self.child.wait()?.code() {
+                let message = "command could not be run";
+                return Err(io::Error::new(io::ErrorKind::NotFound, message));
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl Drop for CommandInput {
+    fn drop(&mut self) {
+        self.output.take();
+        let _ = self.child.wait();
+    }
+}
+
+// How a redirected `print` opens its target the first time it is used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    Truncate,
+    Append,
+    Pipe,
+}
+
+// Feeds a command started by `print | "cmd"` through its stdin. Dropping it
+// closes the pipe and waits for the command to finish.
+struct PipeOutput {
+    child: Child,
+    input: Option<ChildStdin>,
+}
+
+impl PipeOutput {
+    fn open(command_line: &str) -> Result<Self> {
+        let mut child =
+            Command::new("sh").arg("-c").arg(command_line).stdin(Stdio::piped()).spawn()?;
+        let input = child.stdin.take();
+        Ok(Self { child, input })
+    }
+}
+
+impl Write for PipeOutput {
+    fn write(&mut self, buffer: &[u8]) -> Result<usize> {
+        match self.input.as_mut() {
+            Some(input) => input.write(buffer),
+            None => Ok(0),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self.input.as_mut() {
+            Some(input) => input.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for PipeOutput {
+    fn drop(&mut self) {
+        self.input.take();
+        let _ = self.child.wait();
+    }
 }
 
 impl AwkIO {
     pub fn new() -> Self {
         Self {
+            main_input: None,
             inputs: HashMap::new(),
             outputs: HashMap::new(),
             fields: vec![],
+            fields_split: true,
+            field_separator: " ".to_string(),
+            output_separator: " ".to_string(),
+            record_dirty: false,
             line: String::new(),
+            #[cfg(test)]
+            splits: 0,
+            #[cfg(test)]
+            rebuilds: 0,
         }
     }
 
-    pub fn add_input(&mut self, file_path: &str) -> Result<()> {
-        if file_path == "-" {
-            self.inputs.insert("STDIN".to_string(), None);
-            Ok(())
-        } else {
-            let handle = File::open(file_path)?;
-            let buffer = BufReader::new(handle);
-            self.inputs
-                .insert(file_path.to_string(), Some(Box::new(buffer)));
-            Ok(())
-        }
+    /// Makes `file_path` the main input, replacing the one before it; "-"
+    /// is standard input.
+    pub fn open_main_input(&mut self, file_path: &str) -> Result<()> {
+        self.main_input = Some(Input::open(file_path)?);
+        Ok(())
     }
 
-    pub fn add_output(&mut self, file_path: &str) -> Result<()> {
-        if file_path == "-" {
-            self.outputs
-                .insert("STDOUT".to_string(), Box::new(io::stdout()));
-            Ok(())
-        } else {
-            let handle = File::create(file_path)?;
-            let buffer = BufWriter::new(handle);
-            self.outputs.insert(file_path.to_string(), Box::new(buffer));
-            Ok(())
+    pub fn close_main_input(&mut self) {
+        self.main_input = None;
+    }
+
+    pub fn read_main_input(&mut self, buffer: &mut String) -> Result<usize> {
+        match self.main_input.as_mut() {
+            Some(input) => input.read_line(buffer),
+            None => Ok(0),
         }
     }
 
-    pub fn read_line_from_input(
-        &mut self,
-        file_path: &str,
-        delimiter: char,
-    ) -> Result<usize> {
-        if let Some(input) = self.inputs.get_mut(file_path) {
-            let line_len: usize = match input {
-                Some(input) => input.read_line(&mut self.line)?,
-                None => io::stdin().read_line(&mut self.line)?,
-            };
+    pub fn add_input(&mut self, file_path: &str) -> Result<()> {
+        let input = Input::open(file_path)?;
+        self.inputs.insert(file_path.to_string(), input);
+        Ok(())
+    }
 
-            if !self.line.is_empty() {
-                self.fields = self.line
-                    .trim()
-                    .split(delimiter)
-                    .map(|s| s.to_string())
-                    .collect();
-                Ok(line_len)
-            } else {
-                Ok(0)
-            }
-        } else {
-            Ok(0)
+    pub fn add_command_input(&mut self, command_line: &str) -> Result<()> {
+        let buffer = BufReader::new(CommandInput::open(command_line)?);
+        self.inputs.insert(command_line.to_string(), Input::Reader(Box::new(buffer)));
+        Ok(())
+    }
+
+    /// Opens the target of a redirected `print` unless it is already open;
+    /// like other awks, `>` only truncates a file the first time.
+    pub fn open_output(&mut self, name: &str, mode: OutputMode) -> Result<()> {
+        if self.outputs.contains_key(name) {
+            return Ok(());
         }
+
+        let output: Box<dyn Write> = match (name, mode) {
+            (_, OutputMode::Pipe) => Box::new(PipeOutput::open(name)?),
+            ("/dev/stdout" | "-", _) => Box::new(io::stdout()),
+            ("/dev/stderr", _) => Box::new(io::stderr()),
+            (_, OutputMode::Truncate) => Box::new(BufWriter::new(File::create(name)?)),
+            (_, OutputMode::Append) => {
+                let handle = File::options().append(true).create(true).open(name)?;
+                Box::new(BufWriter::new(handle))
+            }
+        };
+        self.outputs.insert(name.to_string(), output);
+        Ok(())
+    }
+
+    /// Makes `line` the current record, to be split on `delimiter` when a
+    /// field is first needed.
+    pub fn set_record(&mut self, line: String, delimiter: &str) {
+        self.line = line;
+        self.field_separator = delimiter.to_string();
+        self.fields_split = false;
+        self.record_dirty = false;
+    }
+
+    pub fn has_input(&self, file_path: &str) -> bool {
+        self.inputs.contains_key(file_path)
     }
 
     pub fn read_from_input(&mut self, file_path: &str, buffer: &mut String) -> Result<usize> {
         if let Some(input) = self.inputs.get_mut(file_path) {
-            match input {
-                Some(input) => input.read_line(buffer),
-                None => io::stdin().read_line(buffer),
-            }
+            input.read_line(buffer)
         } else {
             Ok(0)
         }
     }
 
-    pub fn write_to_output(&mut self, file_path: &str, data: &[u8]) -> Result<()> {
-        if let Some(output) = self.outputs.get_mut(file_path) {
-            output.write_all(data)?;
-            Ok(())
-        } else {
-            Ok(())
+    /// Writes to the output opened under `name`, or to standard output when
+    /// there is none.
+    pub fn write_to_output(&mut self, name: Option<&str>, data: &[u8]) -> Result<()> {
+        match name {
+            None => io::stdout().write_all(data),
+            Some(name) => match self.outputs.get_mut(name) {
+                Some(output) => output.write_all(data),
+                None => Ok(()),
+            },
         }
     }
 
-    pub fn read_until_regex(&mut self, file_path: &str, pattern: Regex) -> Result<usize> {
-        if let Some(input) = self.inputs.get_mut(file_path) {
-            self.line.clear();
-            let mut read_buffer = String::new();
-            let mut delimiter_found = false;
-            let mut bytes_read = 0;
-
-            while !delimiter_found {
-                match input {
-                    Some(input) => bytes_read = input.read_line(&mut read_buffer)?,
-                    None => bytes_read = io::stdin().read_line(&mut read_buffer)?,
-                }
-
-                if bytes_read == 0 {
-                    break;
-                }
-
-                self.line.push_str(&read_buffer);
-
-                if pattern.is_match(&self.line) {
-                    delimiter_found = true;
-                }
-
-                self.fields = pattern
-                    .split(&read_buffer)
-                    .map(|s| s.to_string())
-                    .collect();
-            }
+    /// Closes the input and output opened under `name`, flushing a writer
+    /// first; the main input is never among them. Reading and writing the
+    /// same name use separate handles, so a file can be read with `getline`
+    /// while it is printed to. Returns 0 on
+    /// success and -1 when nothing by that name is open or the final flush
+    /// fails.
+    pub fn close(&mut self, name: &str) -> i32 {
+        let input = self.inputs.remove(name);
+        if let Some(mut output) = self.outputs.remove(name) {
+            return match output.flush() {
+                Ok(()) => 0,
+                Err(_) => -1,
+            };
+        }
+        match input {
+            Some(_) => 0,
+            None => -1,
+        }
+    }
+
+    /// Flushes standard output and closes every other output, waiting for
+    /// piped commands, before the interpreter exits.
+    pub fn close_outputs(&mut self) {
+        let _ = self.flush_all();
+        let names: Vec<String> = self.outputs.keys().cloned().collect();
+        for name in names {
+            self.close(&name);
+        }
+    }
+
+    /// Flushes every open output so a child process's output follows
+    /// what the program has already printed.
+    pub fn flush_all(&mut self) -> Result<()> {
+        for output in self.outputs.values_mut() {
+            output.flush()?;
+        }
+        io::stdout().flush()
+    }
+
+    /// Flushes the output opened under `name`. Returns 0 on success and -1
+    /// when nothing by that name is open or the flush fails.
+    pub fn flush(&mut self, name: &str) -> i32 {
+        match self.outputs.get_mut(name).map(|output| output.flush()) {
+            Some(Ok(())) => 0,
+            _ => -1,
+        }
+    }
+
+    pub fn has_output(&self, name: &str) -> bool {
+        self.outputs.contains_key(name)
+    }
+
+    fn split_record(&mut self) {
+        if self.fields_split {
+            return;
+        }
 
-            Ok(bytes_read)
+        // FS is read the way `split` reads its separator. An empty record
+        // (including $0 before any input is read) has no fields at all,
+        // whatever the separator.
+        self.fields = if self.line.is_empty() {
+            vec![]
         } else {
-            Ok(0)
+            split_fields(&self.line, &self.field_separator)
+        };
+        self.fields_split = true;
+        #[cfg(test)]
+        {
+            self.splits += 1;
         }
     }
 
-    pub fn get_field(&self, index: usize) -> String {
-        if index > 0 && index <= self.fields.len() {
+    pub fn field_count(&mut self) -> usize {
+        self.split_record();
+        self.fields.len()
+    }
+
+    pub fn set_field(&mut self, index: usize, value: &str, output_separator: &str) {
+        if index == 0 {
+            return;
+        }
+
+        self.split_record();
+
+        if index > self.fields.len() {
+            self.fields.resize(index, String::new());
+        }
+
+        self.fields[index - 1] = value.to_string();
+
+        // $0 is rebuilt on its next read, so assigning several fields in a
+        // row only joins them once.
+        self.output_separator = output_separator.to_string();
+        self.record_dirty = true;
+    }
+
+    /// Assigning NF drops the fields past it or adds empty ones, and $0 is
+    /// rebuilt from what is left.
+    pub fn set_field_count(&mut self, count: usize, output_separator: &str) {
+        self.split_record();
+        self.fields.resize(count, String::new());
+        self.output_separator = output_separator.to_string();
+        self.record_dirty = true;
+    }
+
+    pub fn rebuild_record(&mut self, output_separator: &str) {
+        self.line = self.fields.join(output_separator);
+        self.record_dirty = false;
+        #[cfg(test)]
+        {
+            self.rebuilds += 1;
+        }
+    }
+
+    pub fn get_field(&mut self, index: usize) -> String {
+        if index == 0 {
+            if self.record_dirty {
+                let output_separator = self.output_separator.clone();
+                self.rebuild_record(&output_separator);
+            }
+            return self.line.clone();
+        }
+
+        self.split_record();
+        if index <= self.fields.len() {
             self.fields[index - 1].clone()
         } else {
             String::new()
@@ -142,33 +415,56 @@ impl AwkIO {
 
 impl fmt::Display for AwkIO {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "Fields: {:?}", self.fields);
+        writeln!(f, "Fields: {:?}", self.fields)?;
         writeln!(f, "Line: {}", self.line)
     }
 }
 
-impl PartialEq for AwkIO {
-    fn eq(&self, other: &Self) -> bool {
-        self.line == other.line
-    }
-}
-
 impl fmt::Debug for AwkIO {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("AwkIO")
-            .field("fields", &self.fields)
-            .field("line", &self.line)
-            .finish()
+        f.debug_struct("AwkIO").field("fields", &self.fields).field("line", &self.line).finish()
     }
 }
 
 impl Clone for AwkIO {
     fn clone(&self) -> Self {
         let mut new_instance = AwkIO::new();
-        
+
         new_instance.fields = self.fields.clone();
+        new_instance.fields_split = self.fields_split;
+        new_instance.field_separator = self.field_separator.clone();
+        new_instance.output_separator = self.output_separator.clone();
+        new_instance.record_dirty = self.record_dirty;
         new_instance.line = self.line.clone();
-        
+
         new_instance
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_split_only_when_a_field_is_needed() {
+        let mut io = AwkIO::new();
+        io.set_record("a b c".to_string(), " ");
+        assert_eq!(io.get_field(0), "a b c");
+        assert_eq!(io.splits, 0);
+        assert_eq!((io.get_field(2), io.field_count()), ("b".to_string(), 3));
+        assert_eq!(io.splits, 1);
+    }
+
+    #[test]
+    fn assigning_several_fields_rebuilds_the_record_once() {
+        let mut io = AwkIO::new();
+        io.set_record("a b".to_string(), " ");
+        io.set_field(1, "x", "-");
+        io.set_field(3, "y", "-");
+        io.set_field(5, "z", "-");
+        assert_eq!(io.rebuilds, 0);
+        assert_eq!(io.get_field(0), "x-b-y--z");
+        assert_eq!(io.get_field(0), "x-b-y--z");
+        assert_eq!(io.rebuilds, 1);
+    }
+}
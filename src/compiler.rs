@@ -0,0 +1,1021 @@
+use std::collections::HashSet;
+
+use crate::exit_err;
+use crate::machine::{GetlineTarget, Instruction};
+use crate::parser::{AstNode, Constant};
+use crate::value::{get_or_compile, Value};
+
+/// A user-defined function: where its body starts in the instruction stream
+/// and the names of its parameters.
+pub struct Function {
+    pub name: String,
+    pub entry: usize,
+    pub parameters: Vec<String>,
+}
+
+pub struct CompiledProgram {
+    pub instructions: Vec<Instruction>,
+    pub functions: Vec<Function>,
+    // Where the END actions begin; `exit` outside them jumps here.
+    pub end_start: usize,
+}
+
+// The `break` and `continue` jumps of a loop being compiled, patched once
+// the loop's exit and continuation points are known.
+#[derive(Default)]
+struct LoopJumps {
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+struct Compiler {
+    instructions: Vec<Instruction>,
+    functions: Vec<Function>,
+    function_names: HashSet<String>,
+    in_function: bool,
+    record_loop_start: Option<usize>,
+    loops: Vec<LoopJumps>,
+    range_count: usize,
+    optimize: bool,
+}
+
+/// Compiles `program` to VM instructions. With `optimize` set, arithmetic
+/// over numeric constants is folded at compile time.
+pub fn compile(program: &AstNode, optimize: bool) -> CompiledProgram {
+    let mut compiler = Compiler {
+        instructions: vec![],
+        functions: vec![],
+        function_names: HashSet::new(),
+        in_function: false,
+        record_loop_start: None,
+        loops: Vec::new(),
+        range_count: 0,
+        optimize,
+    };
+
+    let end_start = compiler.compile_program(program);
+    CompiledProgram {
+        instructions: compiler.instructions,
+        functions: compiler.functions,
+        end_start,
+    }
+}
+
+impl Compiler {
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    fn emit_jump(&mut self, jump: fn(usize) -> Instruction) -> usize {
+        self.emit(jump(0))
+    }
+
+    fn patch_jump(&mut self, position: usize) {
+        let target = self.instructions.len();
+        match &mut self.instructions[position] {
+            Instruction::Jump(jump_target)
+            | Instruction::JumpIfFalse(jump_target)
+            | Instruction::JumpIfTrue(jump_target)
+            | Instruction::ReadRecord(jump_target)
+            | Instruction::ForInNext(_, jump_target) => *jump_target = target,
+            other => {
+                exit_err!("Cannot patch non-jump instruction {:?}", other);
+            }
+        }
+    }
+
+    // Returns the position of the END actions.
+    fn compile_program(&mut self, program: &AstNode) -> usize {
+        let rules = match program {
+            AstNode::Program(rules) => rules,
+            other => {
+                exit_err!("Expected a program, found {:?}", other);
+            }
+        };
+
+        self.function_names = rules
+            .iter()
+            .filter_map(|rule| match rule {
+                AstNode::FunctionDefinition(name, _, _) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+
+        for rule in rules.iter().filter(|rule| rule_pattern(rule) == Some("BEGIN")) {
+            self.compile_rule_action(rule);
+        }
+
+        let record_rules: Vec<&AstNode> = rules
+            .iter()
+            .filter(|rule| {
+                matches!(rule, AstNode::PatternActionRule(..))
+                    && !matches!(rule_pattern(rule), Some("BEGIN") | Some("END"))
+            })
+            .collect();
+        let has_end_rules = rules.iter().any(|rule| rule_pattern(rule) == Some("END"));
+
+        // A program made only of BEGIN actions never reads its input.
+        if !record_rules.is_empty() || has_end_rules {
+            let loop_start = self.instructions.len();
+            let end_of_input = self.emit_jump(Instruction::ReadRecord);
+            self.record_loop_start = Some(loop_start);
+            for rule in record_rules {
+                self.compile_record_rule(rule);
+            }
+            self.record_loop_start = None;
+            self.emit(Instruction::Jump(loop_start));
+            self.patch_jump(end_of_input);
+        }
+
+        let end_start = self.instructions.len();
+        for rule in rules.iter().filter(|rule| rule_pattern(rule) == Some("END")) {
+            self.compile_rule_action(rule);
+        }
+
+        // Function bodies follow the rules; the program jumps over them to
+        // finish once its last rule has run.
+        let end_target = self.emit_jump(Instruction::Jump);
+        for rule in rules {
+            if let AstNode::FunctionDefinition(name, parameters, body) = rule {
+                self.compile_function(name, parameters, body);
+            }
+        }
+        self.patch_jump(end_target);
+        end_start
+    }
+
+    fn compile_function(&mut self, name: &str, parameters: &[String], body: &AstNode) {
+        if self.functions.iter().any(|function| function.name == name) {
+            exit_err!("Function `{}` is defined more than once", name);
+        }
+
+        let entry = self.instructions.len();
+        self.in_function = true;
+        self.compile_statement(body);
+        self.in_function = false;
+
+        // Falling off the end of a function returns the uninitialized value.
+        self.emit(Instruction::PushValue(Value::Uninitialized));
+        self.emit(Instruction::Return);
+
+        self.functions.push(Function {
+            name: name.to_string(),
+            entry,
+            parameters: parameters.to_vec(),
+        });
+    }
+
+    fn compile_record_rule(&mut self, rule: &AstNode) {
+        let (pattern, action) = match rule {
+            AstNode::PatternActionRule(pattern, action) => (pattern, action),
+            other => {
+                exit_err!("Expected a pattern-action rule, found {:?}", other);
+            }
+        };
+
+        match pattern.as_deref() {
+            Some(AstNode::PatternExpression(pattern)) => {
+                self.compile_expression(pattern);
+                let skip_target = self.emit_jump(Instruction::JumpIfFalse);
+                self.compile_statement(action);
+                self.patch_jump(skip_target);
+            }
+            Some(AstNode::RangePattern(start, end)) => {
+                // Each range keeps whether it is active in a variable of its
+                // own, named so that no program can refer to it.
+                let active_flag = format!("range#{}", self.range_count);
+                self.range_count += 1;
+
+                self.emit(Instruction::LoadVariable(active_flag.clone()));
+                let active_target = self.emit_jump(Instruction::JumpIfTrue);
+                self.compile_expression(start);
+                let skip_target = self.emit_jump(Instruction::JumpIfFalse);
+                self.emit(Instruction::PushValue(Value::Bool(true)));
+                self.emit(Instruction::StoreVariable(active_flag.clone()));
+
+                // The end pattern is checked on the starting record too, so a
+                // record matching both selects only itself.
+                self.patch_jump(active_target);
+                self.compile_expression(end);
+                let action_target = self.emit_jump(Instruction::JumpIfFalse);
+                self.emit(Instruction::PushValue(Value::Bool(false)));
+                self.emit(Instruction::StoreVariable(active_flag));
+
+                self.patch_jump(action_target);
+                self.compile_statement(action);
+                self.patch_jump(skip_target);
+            }
+            Some(other) => {
+                exit_err!("Pattern {:?} is not supported yet", other);
+            }
+            None => self.compile_statement(action),
+        }
+    }
+
+    fn compile_rule_action(&mut self, rule: &AstNode) {
+        if let AstNode::PatternActionRule(_, action) = rule {
+            self.compile_statement(action);
+        }
+    }
+
+    fn compile_statement(&mut self, statement: &AstNode) {
+        match statement {
+            AstNode::Action(statement_list) => {
+                self.compile_statement(statement_list)
+            }
+            AstNode::StatementList(statements) => {
+                for statement in statements {
+                    self.compile_statement(statement);
+                }
+            }
+            AstNode::Nil => {}
+            AstNode::IfStatement(condition, if_body, else_body) => {
+                self.compile_expression(condition);
+                let else_target = self.emit_jump(Instruction::JumpIfFalse);
+                self.compile_statement(if_body);
+
+                match else_body {
+                    Some(else_body) => {
+                        let end_target = self.emit_jump(Instruction::Jump);
+                        self.patch_jump(else_target);
+                        self.compile_statement(else_body);
+                        self.patch_jump(end_target);
+                    }
+                    None => self.patch_jump(else_target),
+                }
+            }
+            AstNode::WhileStatement(condition, body) => {
+                let loop_start = self.instructions.len();
+                self.compile_expression(condition);
+                let exit_target = self.emit_jump(Instruction::JumpIfFalse);
+                self.compile_loop_body(body);
+                self.emit(Instruction::Jump(loop_start));
+                self.patch_jump(exit_target);
+                self.end_loop();
+            }
+            // The body runs before the first test, so it always runs once.
+            AstNode::DoWhileStatement(body, condition) => {
+                let body_start = self.instructions.len();
+                self.compile_loop_body(body);
+                self.compile_expression(condition);
+                self.emit(Instruction::JumpIfTrue(body_start));
+                self.end_loop();
+            }
+            AstNode::ForStatement(initializer, condition, iterator, body) => {
+                self.compile_statement(initializer);
+                let loop_start = self.instructions.len();
+                let exit_target = condition.as_ref().map(|condition| {
+                    self.compile_expression(condition);
+                    self.emit_jump(Instruction::JumpIfFalse)
+                });
+                self.compile_loop_body(body);
+                if let Some(iterator) = iterator {
+                    self.compile_statement(iterator);
+                }
+                self.emit(Instruction::Jump(loop_start));
+                if let Some(exit_target) = exit_target {
+                    self.patch_jump(exit_target);
+                }
+                self.end_loop();
+            }
+            // The array's keys stay on the stack while the loop runs; the
+            // last FOR_IN_NEXT pops them, and a `break` has to as well.
+            AstNode::ForInStatement(variable_name, array_name, body) => {
+                self.emit(Instruction::LoadArgument(array_name.clone()));
+                let loop_start = self.instructions.len();
+                let exit_target = self.emit(Instruction::ForInNext(variable_name.clone(), 0));
+                self.compile_loop_body(body);
+                self.emit(Instruction::Jump(loop_start));
+                self.end_loop();
+                self.emit(Instruction::Pop);
+                self.patch_jump(exit_target);
+            }
+            AstNode::BreakStatement => {
+                let jump = self.emit_jump(Instruction::Jump);
+                self.innermost_loop("break").breaks.push(jump);
+            }
+            AstNode::ContinueStatement => {
+                let jump = self.emit_jump(Instruction::Jump);
+                self.innermost_loop("continue").continues.push(jump);
+            }
+            AstNode::PrintStatement(expression_list, redirection) => {
+                let argument_count = match expression_list.as_deref() {
+                    Some(AstNode::ExpressionList(expressions)) => {
+                        for expression in expressions {
+                            self.compile_expression(expression);
+                        }
+                        expressions.len()
+                    }
+                    _ => 0,
+                };
+                self.compile_redirection(redirection.as_deref());
+                self.emit(Instruction::Print(argument_count));
+            }
+            AstNode::PrintfStatement(format_string, arguments, redirection) => {
+                self.compile_expression(format_string);
+                let argument_count = match arguments.as_ref() {
+                    AstNode::ExpressionList(expressions) => {
+                        for expression in expressions {
+                            self.compile_expression(expression);
+                        }
+                        expressions.len()
+                    }
+                    _ => 0,
+                };
+                self.compile_redirection(redirection.as_deref());
+                self.emit(Instruction::Printf(argument_count + 1));
+            }
+            AstNode::ExitStatement(expression) => {
+                // A bare `exit` keeps the status of an earlier one.
+                match expression {
+                    Some(expression) => self.compile_expression(expression),
+                    None => {
+                        self.emit(Instruction::PushValue(Value::Uninitialized));
+                    }
+                }
+                self.emit(Instruction::Exit);
+            }
+            AstNode::ReturnStatement(expression) => {
+                if !self.in_function {
+                    exit_err!("`return` used outside of a function body");
+                }
+                match expression {
+                    Some(expression) => self.compile_expression(expression),
+                    None => {
+                        self.emit(Instruction::PushValue(Value::Uninitialized));
+                    }
+                }
+                self.emit(Instruction::Return);
+            }
+            AstNode::NextStatement => {
+                let loop_start = self.record_loop_start("next");
+                self.emit(Instruction::Jump(loop_start));
+            }
+            AstNode::NextFileStatement => {
+                let loop_start = self.record_loop_start("nextfile");
+                self.emit(Instruction::NextFile);
+                self.emit(Instruction::Jump(loop_start));
+            }
+            AstNode::DeleteStatement(target) => match target.as_ref() {
+                AstNode::ArrayElement(array_name, subscripts) => {
+                    self.compile_subscript(subscripts);
+                    self.emit(Instruction::DeleteElement(array_name.clone()));
+                }
+                AstNode::Variable(array_name) => {
+                    self.emit(Instruction::DeleteArray(array_name.clone()));
+                }
+                other => {
+                    exit_err!("Cannot delete {:?}", other);
+                }
+            },
+            expression => {
+                self.compile_expression(expression);
+                self.emit(Instruction::Pop);
+            }
+        }
+    }
+
+    // Compiles a loop body, leaving `continue` to go to whatever follows it:
+    // the condition, or the increment of a `for`. The loop's exit must be
+    // compiled before `end_loop` resolves its `break`s.
+    fn compile_loop_body(&mut self, body: &AstNode) {
+        self.loops.push(LoopJumps::default());
+        self.compile_statement(body);
+        if let Some(jumps) = self.loops.last_mut() {
+            for jump in std::mem::take(&mut jumps.continues) {
+                self.patch_jump(jump);
+            }
+        }
+    }
+
+    fn end_loop(&mut self) {
+        if let Some(jumps) = self.loops.pop() {
+            for jump in jumps.breaks {
+                self.patch_jump(jump);
+            }
+        }
+    }
+
+    fn innermost_loop(&mut self, statement: &str) -> &mut LoopJumps {
+        match self.loops.last_mut() {
+            Some(jumps) => jumps,
+            None => {
+                exit_err!("`{}` used outside of a loop", statement);
+            }
+        }
+    }
+
+    // `next` and `nextfile` jump back to read the next record, so they are
+    // only meaningful in the actions of record rules.
+    fn record_loop_start(&self, statement: &str) -> usize {
+        match self.record_loop_start {
+            Some(loop_start) if !self.in_function => loop_start,
+            _ => {
+                exit_err!("`{}` used outside of a rule over input records", statement);
+            }
+        }
+    }
+
+    fn compile_expression(&mut self, expression: &AstNode) {
+        if self.optimize {
+            if let Some(folded) = fold_constant(expression) {
+                self.emit(Instruction::PushValue(folded));
+                return;
+            }
+        }
+
+        match expression {
+            AstNode::Constant(constant) => {
+                self.emit(Instruction::PushValue(constant_value(constant)));
+            }
+            AstNode::Variable(variable_name) => {
+                self.emit(Instruction::LoadVariable(variable_name.clone()));
+            }
+            // A bare regex matches against the current record.
+            AstNode::RegexLiteral(pattern) => {
+                self.emit_regex_pattern(pattern);
+                self.emit(Instruction::MatchRecord);
+            }
+            AstNode::ArrayElement(array_name, subscripts) => {
+                self.compile_subscript(subscripts);
+                self.emit(Instruction::LoadAssociativeArrayValue(array_name.clone()));
+            }
+            // Testing membership never creates the element.
+            AstNode::InExpression(subscripts, array_name) => {
+                self.compile_subscript(subscripts);
+                self.emit(Instruction::ArrayIn(array_name.clone()));
+            }
+            AstNode::AssignmentExpression(target, value) => {
+                self.compile_expression(value);
+                self.emit(Instruction::Duplicate);
+                self.compile_store(target);
+            }
+            AstNode::CompoundAssignmentExpression(target, operator, value) => {
+                self.compile_update(target, false, |compiler| {
+                    compiler.compile_expression(value);
+                    compiler.emit(binary_instruction(operator));
+                });
+            }
+            AstNode::GetlineExpression(target, source) => {
+                self.compile_getline(target.as_deref(), source.as_deref())
+            }
+            AstNode::TernaryExpression(condition, if_true, if_false) => {
+                self.compile_expression(condition);
+                let else_target = self.emit_jump(Instruction::JumpIfFalse);
+                self.compile_expression(if_true);
+                let end_target = self.emit_jump(Instruction::Jump);
+                self.patch_jump(else_target);
+                self.compile_expression(if_false);
+                self.patch_jump(end_target);
+            }
+            AstNode::LogicalOrExpression(first, rest) => {
+                self.compile_short_circuit(first, rest, Instruction::JumpIfTrue, true)
+            }
+            AstNode::LogicalAndExpression(first, rest) | AstNode::AndExpression(first, rest) => {
+                self.compile_short_circuit(first, rest, Instruction::JumpIfFalse, false)
+            }
+            AstNode::MatchExpression(left, operator, right) => {
+                self.compile_expression(left);
+                match right.as_ref() {
+                    // A constant string is a regex known before the program
+                    // runs, just like a literal.
+                    AstNode::Constant(Constant::StringLiteral(pattern)) => {
+                        self.emit_regex_pattern(pattern)
+                    }
+                    right => self.compile_regex_argument(right),
+                }
+                match operator.as_str() {
+                    "~" => self.emit(Instruction::EreMatch),
+                    _ => self.emit(Instruction::EreNonMatch),
+                };
+            }
+            AstNode::EqualityExpression(left, operator, right)
+            | AstNode::RelationalExpression(left, operator, right)
+            | AstNode::AdditiveExpression(left, operator, right)
+            | AstNode::MultiplicativeExpression(left, operator, right) => {
+                self.compile_expression(left);
+                self.compile_expression(right);
+                self.emit(binary_instruction(operator));
+            }
+            AstNode::ExponentExpression(base, exponent) => {
+                self.compile_expression(base);
+                self.compile_expression(exponent);
+                self.emit(Instruction::Exp);
+            }
+            AstNode::ConcatenationExpression(left, right) => {
+                self.compile_expression(left);
+                self.compile_expression(right);
+                self.emit(Instruction::Concatenate);
+            }
+            AstNode::FieldReference(index) => {
+                self.compile_expression(index);
+                self.emit(Instruction::FieldRef);
+            }
+            AstNode::FunctionCall(function_name, arguments) => {
+                let arguments = match arguments.as_ref() {
+                    Some(AstNode::ArgumentList(arguments)) => arguments.as_slice(),
+                    _ => &[],
+                };
+                self.compile_function_call(function_name, arguments);
+            }
+            AstNode::IndirectFunctionCall(variable_name, arguments) => {
+                let arguments = match arguments.as_ref() {
+                    Some(AstNode::ArgumentList(arguments)) => arguments.as_slice(),
+                    _ => &[],
+                };
+                for argument in arguments {
+                    self.compile_argument(argument);
+                }
+                self.emit(Instruction::LoadVariable(variable_name.clone()));
+                self.emit(Instruction::IndirectFunctionCall(argument_names(arguments)));
+            }
+            AstNode::UnaryExpression(operator, target) if operator == "++" || operator == "--" => {
+                self.compile_update(target, false, |compiler| {
+                    compiler.emit(step_instruction(operator));
+                });
+            }
+            // The old value is left behind, as a number even when the
+            // variable held a string.
+            AstNode::PostfixExpression(target, operator) => {
+                self.compile_update(target, true, |compiler| {
+                    compiler.emit(step_instruction(operator));
+                });
+            }
+            AstNode::UnaryExpression(operator, operand) => {
+                self.compile_expression(operand);
+                match operator.as_str() {
+                    "-" => self.emit(Instruction::Neg),
+                    "+" => self.emit(Instruction::Pos),
+                    "!" => self.emit(Instruction::Not),
+                    other => {
+                        exit_err!("Unary operator `{}` is not supported", other);
+                    }
+                };
+            }
+            other => {
+                exit_err!("Cannot compile expression {:?} yet", other);
+            }
+        }
+    }
+
+    // Leaves a single key on the stack; `a[i, j]` joins its subscripts with
+    // SUBSEP.
+    fn compile_subscript(&mut self, subscripts: &AstNode) {
+        match subscripts {
+            AstNode::ExpressionList(expressions) => {
+                for expression in expressions {
+                    self.compile_expression(expression);
+                }
+                if expressions.len() > 1 {
+                    self.emit(Instruction::JoinSubscripts(expressions.len()));
+                }
+            }
+            subscript => self.compile_expression(subscript),
+        }
+    }
+
+    // A bare name passed to a function may be an array, which goes by
+    // handle; everywhere else a name is read as a scalar.
+    fn compile_argument(&mut self, argument: &AstNode) {
+        match argument {
+            AstNode::Variable(variable_name) => {
+                self.emit(Instruction::LoadArgument(variable_name.clone()));
+            }
+            other => self.compile_expression(other),
+        }
+    }
+
+    fn compile_function_call(&mut self, function_name: &str, arguments: &[AstNode]) {
+        if self.function_names.contains(function_name) {
+            for argument in arguments {
+                self.compile_argument(argument);
+            }
+            let argument_names = argument_names(arguments);
+            self.emit(Instruction::FunctionCall(function_name.to_string(), argument_names));
+            return;
+        }
+
+        if let (Some(instruction), [argument]) =
+            (Instruction::unary_builtin(function_name), arguments)
+        {
+            self.compile_argument(argument);
+            self.emit(instruction);
+            return;
+        }
+
+        match (function_name, arguments) {
+            ("parsekv", [source, AstNode::Variable(array_name), separators @ ..])
+                if separators.len() <= 2 =>
+            {
+                self.compile_expression(source);
+                for separator in separators {
+                    self.compile_expression(separator);
+                }
+                self.emit(Instruction::ParseKvFn(array_name.clone(), arguments.len() - 1));
+            }
+            ("split", [source, AstNode::Variable(array_name), separator @ ..])
+                if separator.len() <= 1 =>
+            {
+                self.compile_expression(source);
+                for separator in separator {
+                    self.compile_regex_argument(separator);
+                }
+                self.emit(Instruction::Split(array_name.clone(), arguments.len() - 1));
+            }
+            ("sprintf", [_, ..]) => {
+                for argument in arguments {
+                    self.compile_expression(argument);
+                }
+                self.emit(Instruction::SprintfFn(arguments.len()));
+            }
+            ("substr", [_, _] | [_, _, _]) => {
+                for argument in arguments {
+                    self.compile_expression(argument);
+                }
+                self.emit(Instruction::SubstrFn(arguments.len()));
+            }
+            ("reverse", [source, AstNode::Variable(target)]) => {
+                self.compile_argument(source);
+                self.emit(Instruction::ReverseFn(Some(target.clone())));
+            }
+            ("atan2", [y, x]) => {
+                self.compile_expression(y);
+                self.compile_expression(x);
+                self.emit(Instruction::Atan2Fn);
+            }
+            ("rand", []) => {
+                self.emit(Instruction::RandFn);
+            }
+            ("srand", [] | [_]) => {
+                for argument in arguments {
+                    self.compile_expression(argument);
+                }
+                self.emit(Instruction::SrandFn(arguments.len()));
+            }
+            ("strip", [_, _]) => {
+                for argument in arguments {
+                    self.compile_expression(argument);
+                }
+                self.emit(Instruction::StripFn(arguments.len()));
+            }
+            ("sub" | "gsub", [regex, replacement, target @ ..]) if target.len() <= 1 => {
+                let record = AstNode::FieldReference(Box::new(AstNode::Constant(
+                    Constant::IntegerLiteral("0".to_string()),
+                )));
+                let target = target.first().unwrap_or(&record);
+                self.compile_substitution(function_name == "gsub", regex, replacement, target);
+            }
+            ("match", [source, regex]) => {
+                self.compile_expression(source);
+                self.compile_regex_argument(regex);
+                self.emit(Instruction::MatchFn);
+            }
+            ("index", [source, pattern]) => {
+                self.compile_expression(source);
+                self.compile_expression(pattern);
+                self.emit(Instruction::IndexOf);
+            }
+            ("fflush", []) => {
+                self.emit(Instruction::PushValue(Value::StringLiteral(String::new())));
+                self.emit(Instruction::FflushFn);
+            }
+            ("length", []) => {
+                self.emit(Instruction::PushValue(Value::Number(0)));
+                self.emit(Instruction::FieldRef);
+                self.emit(Instruction::Length);
+            }
+            _ => {
+                exit_err!(
+                    "Cannot compile call to `{}` with {} arguments yet",
+                    function_name,
+                    arguments.len()
+                );
+            }
+        }
+    }
+
+    // The target is only assigned when something was replaced, so a field
+    // that didn't match leaves the record as it was.
+    fn compile_substitution(
+        &mut self,
+        global: bool,
+        regex: &AstNode,
+        replacement: &AstNode,
+        target: &AstNode,
+    ) {
+        if !matches!(
+            target,
+            AstNode::Variable(_) | AstNode::ArrayElement(_, _) | AstNode::FieldReference(_)
+        ) {
+            exit_err!("The target of sub or gsub must be a variable, element or field");
+        }
+
+        self.compile_regex_argument(regex);
+        self.compile_expression(replacement);
+        self.compile_expression(target);
+        self.emit(if global { Instruction::GsubFn } else { Instruction::SubFn });
+        self.emit(Instruction::Duplicate);
+        let unchanged = self.emit_jump(Instruction::JumpIfFalse);
+        self.emit(Instruction::Swap);
+        self.compile_store(target);
+        let end = self.emit_jump(Instruction::Jump);
+        self.patch_jump(unchanged);
+        self.emit(Instruction::Swap);
+        self.emit(Instruction::Pop);
+        self.patch_jump(end);
+    }
+
+    // Stores the value on top of the stack into an assignable expression,
+    // popping it.
+    fn compile_store(&mut self, target: &AstNode) {
+        match target {
+            AstNode::Variable(variable_name) => {
+                self.emit(Instruction::StoreVariable(variable_name.clone()));
+            }
+            AstNode::ArrayElement(array_name, subscripts) => {
+                self.compile_subscript(subscripts);
+                self.emit(Instruction::StoreAssociativeArrayValue(array_name.clone()));
+            }
+            AstNode::FieldReference(index) => {
+                self.compile_expression(index);
+                self.emit(Instruction::SetField);
+            }
+            other => {
+                exit_err!("Assignment to {:?} is not supported yet", other);
+            }
+        }
+    }
+
+    // Replaces the value of `target` with what `update` computes from it and
+    // leaves the new value, or with `keep_old` the old one as a number. An
+    // element's subscript or a field's index is evaluated once and kept on
+    // the stack, so `a[i++] += 1` steps `i` once.
+    fn compile_update(
+        &mut self,
+        target: &AstNode,
+        keep_old: bool,
+        update: impl FnOnce(&mut Self),
+    ) {
+        let (load, store) = match target {
+            AstNode::ArrayElement(array_name, subscripts) => {
+                self.compile_subscript(subscripts);
+                (
+                    Instruction::LoadAssociativeArrayValue(array_name.clone()),
+                    Instruction::StoreAssociativeArrayValue(array_name.clone()),
+                )
+            }
+            AstNode::FieldReference(index) => {
+                self.compile_expression(index);
+                (Instruction::FieldRef, Instruction::SetField)
+            }
+            target => {
+                self.compile_expression(target);
+                if keep_old {
+                    self.emit(Instruction::Pos);
+                    self.emit(Instruction::Duplicate);
+                    update(self);
+                } else {
+                    update(self);
+                    self.emit(Instruction::Duplicate);
+                }
+                self.compile_store(target);
+                return;
+            }
+        };
+        if keep_old {
+            // key key -> key old -> old key -> old key key -> old key new -> old new key -> old
+            self.emit(Instruction::Duplicate);
+            self.emit(load.clone());
+            self.emit(Instruction::Pos);
+            self.emit(Instruction::Swap);
+            self.emit(Instruction::Duplicate);
+            self.emit(load);
+            update(self);
+            self.emit(Instruction::Swap);
+            self.emit(store);
+        } else {
+            // key key key -> key key old -> key key new -> key new key -> key -> new
+            self.emit(Instruction::Duplicate);
+            self.emit(Instruction::Duplicate);
+            self.emit(load.clone());
+            update(self);
+            self.emit(Instruction::Swap);
+            self.emit(store);
+            self.emit(load);
+        }
+    }
+
+    // `getline var` leaves the line under its status; the line is stored only
+    // when the status shows one was read, so the variable is untouched at EOF.
+    fn compile_getline(&mut self, target: Option<&AstNode>, source: Option<&AstNode>) {
+        let getline: fn(GetlineTarget) -> Instruction = match source {
+            None => Instruction::Getline,
+            Some(AstNode::Redirection(operator, file)) if operator == "<" => {
+                self.compile_expression(file);
+                Instruction::GetlineFile
+            }
+            Some(AstNode::Redirection(operator, command)) if operator == "|" => {
+                self.compile_expression(command);
+                Instruction::GetlineCommand
+            }
+            Some(other) => {
+                exit_err!("Unsupported getline source {:?}", other);
+            }
+        };
+
+        let Some(target) = target else {
+            self.emit(getline(GetlineTarget::Record));
+            return;
+        };
+
+        self.emit(getline(GetlineTarget::Stack));
+        self.emit(Instruction::Duplicate);
+        self.emit(Instruction::PushValue(Value::Number(0)));
+        self.emit(Instruction::Gt);
+        let skip_target = self.emit_jump(Instruction::JumpIfFalse);
+        self.emit(Instruction::Swap);
+        self.compile_store(target);
+        self.patch_jump(skip_target);
+    }
+
+    // The target is evaluated after the arguments; the instruction opens it
+    // and sends the following `print` or `printf` there.
+    fn compile_redirection(&mut self, redirection: Option<&AstNode>) {
+        let (operator, target) = match redirection {
+            Some(AstNode::Redirection(operator, target)) => (operator, target),
+            Some(other) => {
+                exit_err!("Expected an output redirection, found {:?}", other);
+            }
+            None => return,
+        };
+
+        self.compile_expression(target);
+        match operator.as_str() {
+            ">" => self.emit(Instruction::OutputToFile),
+            ">>" => self.emit(Instruction::AppendToFile),
+            "|" => self.emit(Instruction::OpenPipe),
+            other => {
+                exit_err!("Unknown output redirection `{}`", other);
+            }
+        };
+    }
+
+    // A regex literal passed to a builtin is the pattern itself, not a match
+    // against $0.
+    fn compile_regex_argument(&mut self, argument: &AstNode) {
+        match argument {
+            AstNode::RegexLiteral(pattern) => self.emit_regex_pattern(pattern),
+            argument => self.compile_expression(argument),
+        }
+    }
+
+    // Constant patterns are checked here, so a bad one is reported even if
+    // the code using it never runs.
+    fn emit_regex_pattern(&mut self, pattern: &str) {
+        if let Err(e) = get_or_compile(pattern) {
+            exit_err!("Invalid regular expression `{}`: {}", pattern, e);
+        }
+        self.emit(Instruction::PushValue(Value::RegexPattern(pattern.to_string())));
+    }
+
+    fn compile_short_circuit(
+        &mut self,
+        first: &AstNode,
+        rest: &[AstNode],
+        jump: fn(usize) -> Instruction,
+        short_circuit_value: bool,
+    ) {
+        let mut short_circuit_targets = vec![];
+
+        for operand in std::iter::once(first).chain(rest) {
+            self.compile_expression(operand);
+            short_circuit_targets.push(self.emit_jump(jump));
+        }
+
+        self.emit(Instruction::PushValue(Value::Bool(!short_circuit_value)));
+        let end_target = self.emit_jump(Instruction::Jump);
+
+        for target in short_circuit_targets {
+            self.patch_jump(target);
+        }
+        self.emit(Instruction::PushValue(Value::Bool(short_circuit_value)));
+        self.patch_jump(end_target);
+    }
+}
+
+fn rule_pattern(rule: &AstNode) -> Option<&str> {
+    match rule {
+        AstNode::PatternActionRule(Some(pattern), _) => match pattern.as_ref() {
+            AstNode::PatternExpression(expression) => match expression.as_ref() {
+                AstNode::Variable(name) if name == "BEGIN" || name == "END" => Some(name),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn constant_value(constant: &Constant) -> Value {
+    match constant {
+        Constant::IntegerLiteral(literal) => match literal.parse::<i64>() {
+            Ok(n) => Value::Number(n),
+            Err(_) => Value::Float(literal.parse().unwrap_or(0.0)),
+        },
+        Constant::FloatingPointLiteral(literal) => Value::Float(literal.parse().unwrap_or(0.0)),
+        Constant::StringLiteral(literal) => Value::StringLiteral(literal.clone()),
+    }
+}
+
+// Folds `+`, `-` and `*` over numeric constants using the same promotion
+// and Value arithmetic as the VM. Division and modulo are left to run time
+// so that division by zero is still reported there.
+fn fold_constant(expression: &AstNode) -> Option<Value> {
+    match expression {
+        AstNode::Constant(
+            constant @ (Constant::IntegerLiteral(_) | Constant::FloatingPointLiteral(_)),
+        ) => Some(constant_value(constant)),
+        AstNode::AdditiveExpression(left, operator, right)
+        | AstNode::MultiplicativeExpression(left, operator, right) => {
+            let (left, right) = match (fold_constant(left)?, fold_constant(right)?) {
+                (left @ Value::Float(_), right) | (left, right @ Value::Float(_)) => {
+                    (Value::Float(left.to_number()), Value::Float(right.to_number()))
+                }
+                operands => operands,
+            };
+
+            match operator.as_str() {
+                "+" => left.add(&right),
+                "-" => left.subtract(&right),
+                "*" => left.multiply(&right),
+                _ => None,
+            }
+        }
+        AstNode::UnaryExpression(operator, operand) if operator == "-" => {
+            Some(-fold_constant(operand)?)
+        }
+        _ => None,
+    }
+}
+
+// Bare variables are passed by name as well as by value; see `compile_argument`.
+fn argument_names(arguments: &[AstNode]) -> Vec<Option<String>> {
+    arguments
+        .iter()
+        .map(|argument| match argument {
+            AstNode::Variable(variable_name) => Some(variable_name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn step_instruction(operator: &str) -> Instruction {
+    match operator {
+        "++" => Instruction::Incr,
+        _ => Instruction::Decr,
+    }
+}
+
+fn binary_instruction(operator: &str) -> Instruction {
+    match operator {
+        "+" => Instruction::Add,
+        "-" => Instruction::Sub,
+        "*" => Instruction::Mul,
+        "/" => Instruction::Div,
+        "%" => Instruction::Mod,
+        "^" | "**" => Instruction::Exp,
+        "==" => Instruction::Eq,
+        "!=" => Instruction::Ne,
+        "<" => Instruction::Lt,
+        "<=" => Instruction::Le,
+        ">" => Instruction::Gt,
+        ">=" => Instruction::Ge,
+        other => {
+            exit_err!("Binary operator `{}` is not supported yet", other);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn listing(source: &str, optimize: bool) -> Vec<String> {
+        let program = parse(source).unwrap();
+        compile(&program, optimize).instructions.iter().map(|i| format!("{:?}", i)).collect()
+    }
+
+    #[test]
+    fn constant_expressions_are_folded_unless_disabled() {
+        let folded = listing("BEGIN { print 2 * 3 + 1 }", true);
+        let pushes: Vec<&String> = folded.iter().filter(|i| i.starts_with("PushValue")).collect();
+        assert_eq!(pushes, ["PushValue(Number(7))"], "{:?}", folded);
+        assert!(!folded.iter().any(|i| i == "Mul" || i == "Add"), "{:?}", folded);
+
+        let unfolded = listing("BEGIN { print 2 * 3 + 1 }", false);
+        assert!(unfolded.iter().any(|i| i == "Mul"), "{:?}", unfolded);
+        assert!(unfolded.iter().any(|i| i == "Add"), "{:?}", unfolded);
+    }
+}
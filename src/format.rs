@@ -0,0 +1,390 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::value::{bignum_mode, characters_as_bytes, raw_byte, Value};
+
+#[derive(Debug, Default)]
+struct FormatSpec {
+    left_justify: bool,
+    show_sign: bool,
+    space_sign: bool,
+    zero_pad: bool,
+    alternate_form: bool,
+    width: Option<usize>,
+    precision: Option<usize>,
+    conversion: char,
+}
+
+/// The default for both CONVFMT and OFMT.
+pub const DEFAULT_NUMBER_FORMAT: &str = "%.6g";
+
+const CONVERSIONS: &str = "diouxXeEfFgGsc";
+
+pub fn format(format: &str, args: &[Value]) -> String {
+    format_with_conversion(format, args, DEFAULT_NUMBER_FORMAT)
+}
+
+/// Like `format`, but `%s` renders non-integral numbers through
+/// `conversion_format`, the program's CONVFMT.
+pub fn format_with_conversion(format: &str, args: &[Value], conversion_format: &str) -> String {
+    let mut output = String::new();
+    let mut args = args.iter();
+    let mut chars = format.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            output.push(ch);
+            continue;
+        }
+
+        if chars.peek() == Some(&'%') {
+            chars.next();
+            output.push('%');
+            continue;
+        }
+
+        // Kept so that a specifier we can't format is copied out verbatim.
+        let specifier = chars.clone();
+        let mut spec = FormatSpec::default();
+
+        while let Some(&flag) = chars.peek() {
+            match flag {
+                '-' => spec.left_justify = true,
+                '+' => spec.show_sign = true,
+                ' ' => spec.space_sign = true,
+                '0' => spec.zero_pad = true,
+                '#' => spec.alternate_form = true,
+                _ => break,
+            }
+            chars.next();
+        }
+
+        // As in C, `*` takes the width or precision from the next argument.
+        // A negative width left-justifies; a negative precision is ignored.
+        if chars.peek() == Some(&'*') {
+            chars.next();
+            let width = next_count(&mut args);
+            spec.left_justify |= width < 0;
+            spec.width = Some(width.unsigned_abs() as usize);
+        } else {
+            spec.width = consume_count(&mut chars);
+        }
+
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            if chars.peek() == Some(&'*') {
+                chars.next();
+                let precision = next_count(&mut args);
+                spec.precision = (precision >= 0).then_some(precision as usize);
+            } else {
+                spec.precision = Some(consume_count(&mut chars).unwrap_or(0));
+            }
+        }
+
+        // As in gawk, an unknown conversion or a `%` dangling at the end of
+        // the format is printed as written and consumes no argument.
+        match chars.peek() {
+            Some(&conversion) if CONVERSIONS.contains(conversion) => {
+                spec.conversion = conversion;
+                chars.next();
+            }
+            _ => {
+                let consumed = specifier.clone().count() - chars.clone().count();
+                output.push('%');
+                output.extend(specifier.take(consumed));
+                if let Some(other) = chars.next() {
+                    output.push(other);
+                }
+                continue;
+            }
+        }
+
+        let argument = args
+            .next()
+            .cloned()
+            .unwrap_or(Value::StringLiteral(String::new()));
+
+        output.push_str(&format_argument(&spec, &argument, conversion_format));
+    }
+
+    output
+}
+
+fn next_count<'a>(args: &mut impl Iterator<Item = &'a Value>) -> i64 {
+    args.next().map_or(0, |argument| argument.to_number() as i64)
+}
+
+fn consume_count(chars: &mut Peekable<Chars>) -> Option<usize> {
+    let mut digits = String::new();
+    while let Some(&ch) = chars.peek() {
+        if ch.is_ascii_digit() {
+            digits.push(ch);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+    digits.parse().ok()
+}
+
+fn format_argument(spec: &FormatSpec, argument: &Value, conversion_format: &str) -> String {
+    match spec.conversion {
+        'd' | 'i' => format_signed(spec, argument),
+        'o' | 'x' | 'X' | 'u' => format_unsigned(spec, argument),
+        'e' | 'E' | 'f' | 'F' | 'g' | 'G' => format_float(spec, argument.to_number()),
+        's' => format_string(spec, &argument.to_string_with_format(conversion_format)),
+        'c' => format_character(spec, argument),
+        other => unreachable!("unchecked format conversion `%{}`", other),
+    }
+}
+
+fn sign_prefix(spec: &FormatSpec, negative: bool) -> &'static str {
+    if negative {
+        "-"
+    } else if spec.show_sign {
+        "+"
+    } else if spec.space_sign {
+        " "
+    } else {
+        ""
+    }
+}
+
+fn pad(spec: &FormatSpec, sign: &str, body: &str, zero_padding_allowed: bool) -> String {
+    let width = spec.width.unwrap_or(0);
+    let length = sign.chars().count() + body.chars().count();
+
+    if length >= width {
+        return format!("{}{}", sign, body);
+    }
+
+    let padding = width - length;
+
+    if spec.left_justify {
+        format!("{}{}{}", sign, body, " ".repeat(padding))
+    } else if spec.zero_pad && zero_padding_allowed {
+        format!("{}{}{}", sign, "0".repeat(padding), body)
+    } else {
+        format!("{}{}{}", " ".repeat(padding), sign, body)
+    }
+}
+
+fn apply_integer_precision(spec: &FormatSpec, digits: String) -> String {
+    match spec.precision {
+        Some(0) if digits == "0" => String::new(),
+        Some(precision) if digits.len() < precision => {
+            format!("{}{}", "0".repeat(precision - digits.len()), digits)
+        }
+        _ => digits,
+    }
+}
+
+fn format_non_finite(spec: &FormatSpec, number: f64) -> String {
+    let body = if number.is_nan() { "nan" } else { "inf" };
+    let body = if spec.conversion.is_ascii_uppercase() {
+        body.to_uppercase()
+    } else {
+        body.to_string()
+    };
+    let negative = number.is_infinite() && number.is_sign_negative();
+
+    pad(spec, sign_prefix(spec, negative), &body, false)
+}
+
+fn format_signed(spec: &FormatSpec, argument: &Value) -> String {
+    let integer = match argument.exact_integer() {
+        Some(integer) => integer,
+        None => {
+            let number = argument.to_number();
+            if !number.is_finite() {
+                return format_non_finite(spec, number);
+            }
+            number.trunc() as i64 as i128
+        }
+    };
+    let digits = apply_integer_precision(spec, integer.unsigned_abs().to_string());
+
+    pad(
+        spec,
+        sign_prefix(spec, integer < 0),
+        &digits,
+        spec.precision.is_none(),
+    )
+}
+
+fn format_unsigned(spec: &FormatSpec, argument: &Value) -> String {
+    let number = argument.to_number();
+    if !number.is_finite() {
+        return format_non_finite(spec, number);
+    }
+
+    // Negative values are rendered in two's complement at the width of the
+    // active integer mode: 64 bits by default, 128 bits under --bignum.
+    let integer = if bignum_mode() {
+        argument.exact_integer().unwrap_or(number.trunc() as i128) as u128
+    } else {
+        let exact = argument.exact_integer().and_then(|integer| i64::try_from(integer).ok());
+        exact.unwrap_or(number.trunc() as i64) as u64 as u128
+    };
+    let digits = match spec.conversion {
+        'o' => format!("{:o}", integer),
+        'x' => format!("{:x}", integer),
+        'X' => format!("{:X}", integer),
+        _ => integer.to_string(),
+    };
+    let mut digits = apply_integer_precision(spec, digits);
+
+    let prefix = match spec.conversion {
+        'o' if spec.alternate_form && !digits.starts_with('0') => {
+            digits.insert(0, '0');
+            ""
+        }
+        'x' if spec.alternate_form && integer != 0 => "0x",
+        'X' if spec.alternate_form && integer != 0 => "0X",
+        _ => "",
+    };
+
+    pad(spec, prefix, &digits, spec.precision.is_none())
+}
+
+fn format_float(spec: &FormatSpec, number: f64) -> String {
+    if !number.is_finite() {
+        return format_non_finite(spec, number);
+    }
+
+    let precision = spec.precision.unwrap_or(6);
+    let magnitude = number.abs();
+
+    let mut body = match spec.conversion {
+        'f' | 'F' => format!("{:.*}", precision, magnitude),
+        'e' | 'E' => format_exponential(magnitude, precision),
+        _ => format_general(magnitude, precision, spec.alternate_form),
+    };
+
+    // The alternate form always shows the decimal point.
+    if spec.alternate_form && !body.contains('.') {
+        match body.find('e') {
+            Some(exponent) => body.insert(exponent, '.'),
+            None => body.push('.'),
+        }
+    }
+
+    let body = if spec.conversion.is_ascii_uppercase() {
+        body.to_uppercase()
+    } else {
+        body
+    };
+
+    pad(spec, sign_prefix(spec, number.is_sign_negative() && number != 0.0), &body, true)
+}
+
+fn format_exponential(magnitude: f64, precision: usize) -> String {
+    let rendered = format!("{:.*e}", precision, magnitude);
+    let (mantissa, exponent) = rendered.split_once('e').unwrap_or((&rendered, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    let exponent_sign = if exponent < 0 { '-' } else { '+' };
+
+    format!("{}e{}{:02}", mantissa, exponent_sign, exponent.abs())
+}
+
+fn format_general(magnitude: f64, precision: usize, keep_trailing_zeros: bool) -> String {
+    let precision = precision.max(1);
+
+    let exponent = if magnitude == 0.0 {
+        0
+    } else {
+        let rendered = format!("{:.*e}", precision - 1, magnitude);
+        rendered
+            .split_once('e')
+            .and_then(|(_, exponent)| exponent.parse::<i32>().ok())
+            .unwrap_or(0)
+    };
+
+    if exponent < -4 || exponent >= precision as i32 {
+        let rendered = format_exponential(magnitude, precision - 1);
+        if keep_trailing_zeros {
+            return rendered;
+        }
+        let (mantissa, exponent) = rendered.split_once('e').unwrap();
+        format!("{}e{}", strip_trailing_zeros(mantissa), exponent)
+    } else {
+        let decimals = (precision as i32 - 1 - exponent).max(0) as usize;
+        let rendered = format!("{:.*}", decimals, magnitude);
+        if keep_trailing_zeros {
+            return rendered;
+        }
+        strip_trailing_zeros(&rendered).to_string()
+    }
+}
+
+fn strip_trailing_zeros(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
+// A number is a character code; a string contributes its first character.
+// In byte mode only the low eight bits of a number count, as in gawk, and
+// the byte is printed as is; otherwise a value that isn't a Unicode scalar
+// prints U+FFFD.
+fn format_character(spec: &FormatSpec, argument: &Value) -> String {
+    let character = if argument.is_string() && !argument.is_numeric_string() {
+        argument.to_string().chars().next()
+    } else {
+        let code = argument.to_number();
+        if characters_as_bytes() {
+            Some(raw_byte((code as i64 & 0xFF) as u8))
+        } else if (0.0..=u32::MAX as f64).contains(&code) {
+            Some(char::from_u32(code as u32).unwrap_or(char::REPLACEMENT_CHARACTER))
+        } else {
+            Some(char::REPLACEMENT_CHARACTER)
+        }
+    };
+
+    pad(spec, "", &character.map(String::from).unwrap_or_default(), false)
+}
+
+fn format_string(spec: &FormatSpec, string: &str) -> String {
+    let body: String = match spec.precision {
+        Some(precision) => string.chars().take(precision).collect(),
+        None => string.to_string(),
+    };
+
+    pad(spec, "", &body, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparison_results_format_as_integers() {
+        assert_eq!(format("%d", &[Value::Bool(false)]), "0");
+        assert_eq!(format("%d", &[Value::Bool(true)]), "1");
+    }
+
+    #[test]
+    fn alternate_forms_keep_prefixes_and_points() {
+        let number = |n: i64| Value::Number(n);
+        assert_eq!(format("%#x %#X %#x", &[number(255), number(255), number(0)]), "0xff 0XFF 0");
+        assert_eq!(format("%#o %#o", &[number(8), number(0)]), "010 0");
+        assert_eq!(format("%#.0f %#g", &[number(3), Value::Float(1.5)]), "3. 1.50000");
+    }
+
+    #[test]
+    fn non_finite_numbers_print_as_nan_and_inf() {
+        let (nan, inf) = (Value::Float(f64::NAN), Value::Float(f64::INFINITY));
+        assert_eq!(format("%f %g %e", &[nan.clone(), nan.clone(), nan]), "nan nan nan");
+        assert_eq!(format("%f %g %E", &[inf.clone(), inf.clone(), inf]), "inf inf INF");
+        assert_eq!(format("%5.2f", &[Value::Float(f64::NEG_INFINITY)]), " -inf");
+    }
+
+    #[test]
+    fn c_prints_a_code_point_or_a_first_character() {
+        assert_eq!(format("%c", &[Value::Number(65)]), "A");
+        assert_eq!(format("%c", &[Value::StringLiteral("xyz".to_string())]), "x");
+        assert_eq!(format("%c", &[Value::Number(0x1F600)]), "😀");
+    }
+}
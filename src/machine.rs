@@ -1,20 +1,44 @@
 use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use regex::Regex;
+
+use crate::awkio::{AwkIO, OutputMode};
 use crate::exit_err;
-use crate::value::Value;
+use crate::format::{format_with_conversion, DEFAULT_NUMBER_FORMAT};
+use crate::parser::parse_assignment;
+use crate::value::{
+    get_or_compile, string_bytes, string_length, substitute, take_last_error, Value,
+};
+use crate::PROG_NAME;
 
 #[derive(Debug, Clone)]
-enum Instruction {
-    PushValue,
-    FunctionCall,
-    JumpIfFalse,
-    JumpIfTrue,
-    Jump,
+pub enum Instruction {
+    PushValue(Value),
+    Pop,
+    // Each argument carries the caller's variable name when it is a bare
+    // variable, so that an untyped parameter can become the caller's array.
+    FunctionCall(String, Vec<Option<String>>),
+    IndirectFunctionCall(Vec<Option<String>>),
+    JumpIfFalse(usize),
+    JumpIfTrue(usize),
+    Jump(usize),
     Return,
-    LoadVariable,
-    StoreVariable,
-    LoadAssociativeArrayValue,
-    StoreAssociativeArrayValue,
+    LoadVariable(String),
+    LoadArgument(String),
+    StoreVariable(String),
+    LoadAssociativeArrayValue(String),
+    StoreAssociativeArrayValue(String),
+    ForInNext(String, usize),
+    JoinSubscripts(usize),
+    ArrayIn(String),
+    DeleteElement(String),
+    DeleteArray(String),
+    FieldRef,
+    SetField,
     Duplicate,
     Swap,
     Add,
@@ -23,391 +47,1620 @@ enum Instruction {
     Mod,
     Exp,
     Div,
-    Shr,
-    Shl,
     Eq,
     Ne,
     Gt,
     Ge,
     Lt,
     Le,
-    And,
-    Or,
+    Not,
     Incr,
     Decr,
     Pos,
     Neg,
-    Begin,
-    End,
+    ReadRecord(usize),
     EreMatch,
     EreNonMatch,
-    BitwiseAnd,
-    BitwiseOr,
-    BitwiseXor,
-    BitwiseNot,
-    Print,
-    Printf,
+    Print(usize),
+    Printf(usize),
     OutputToFile,
     AppendToFile,
-    Getline,
+    Getline(GetlineTarget),
+    GetlineFile(GetlineTarget),
+    GetlineCommand(GetlineTarget),
     OpenPipe,
     System,
     CloseStream,
-    AppendOut,
-    RegexMatch,
+    MatchRecord,
     Concatenate,
     Length,
-    Substring,
     IndexOf,
-    Split,
-    Join,
+    Split(String, usize),
     ToLower,
     ToUpper,
-    FormatTime,
-    FormatNumber,
     SinFn,
     CosFn,
-    TanFn,
-    AsinFn,
-    AcosFn,
-    AtanFn,
+    Atan2Fn,
     LogFn,
-    Log10Fn,
     ExpFn,
     SqrtFn,
     IntFn,
-    SubstrFn,
-    SprintfFn,
+    SubstrFn(usize),
+    SprintfFn(usize),
     MatchFn,
     SubFn,
     GsubFn,
-    RindexFn,
-    SrandFn,
+    SrandFn(usize),
     RandFn,
-    AndFn,
-    Next,
+    SlurpFn,
+    StripFn(usize),
+    CommafyFn,
+    ReverseFn(Option<String>),
+    FflushFn,
+    AminFn,
+    AmaxFn,
+    Crc32Fn,
+    ParseKvFn(String, usize),
     NextFile,
     Exit,
 }
 
+/// Where a `getline` puts the line it reads: into `$0`, or onto the stack
+/// (below the status) for the compiler to store into a variable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GetlineTarget {
+    Record,
+    Stack,
+}
+
+impl Instruction {
+    /// The instruction implementing a builtin that takes one argument.
+    pub fn unary_builtin(function_name: &str) -> Option<Instruction> {
+        match function_name {
+            "length" => Some(Instruction::Length),
+            "toupper" => Some(Instruction::ToUpper),
+            "tolower" => Some(Instruction::ToLower),
+            "int" => Some(Instruction::IntFn),
+            "sin" => Some(Instruction::SinFn),
+            "cos" => Some(Instruction::CosFn),
+            "exp" => Some(Instruction::ExpFn),
+            "log" => Some(Instruction::LogFn),
+            "sqrt" => Some(Instruction::SqrtFn),
+            "system" => Some(Instruction::System),
+            "close" => Some(Instruction::CloseStream),
+            "fflush" => Some(Instruction::FflushFn),
+            "amin" => Some(Instruction::AminFn),
+            "amax" => Some(Instruction::AmaxFn),
+            "crc32" => Some(Instruction::Crc32Fn),
+            "slurp" => Some(Instruction::SlurpFn),
+            "strip" => Some(Instruction::StripFn(1)),
+            "commafy" => Some(Instruction::CommafyFn),
+            "reverse" => Some(Instruction::ReverseFn(None)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FunctionEntry {
+    entry: usize,
+    parameters: Vec<String>,
+}
+
 #[derive(Debug, Clone)]
-struct StackVM {
-    stack: Vec<Option<Value>>,
+struct CallFrame {
+    return_pc: usize,
+    locals: HashMap<String, Option<Value>>,
+    // Arrays created for this call's locals, released when it returns.
+    local_arrays: Vec<usize>,
+    // The caller's stack height, restored on return so that anything a
+    // `return` leaves behind, such as the keys of a `for (k in a)` loop it
+    // cut short, is dropped.
+    stack_height: usize,
+    // Parameters bound to an untyped variable of the caller, which becomes an
+    // array too if the parameter is used as one.
+    array_sources: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StackVM {
+    stack: Vec<Value>,
     program: Vec<Instruction>,
     environ: HashMap<String, Option<Value>>,
+    // Every array's elements, indexed by the `Value::Array` handles that
+    // variables hold, with released slots kept for reuse.
+    arrays: Vec<HashMap<String, Box<Value>>>,
+    free_arrays: Vec<usize>,
+    functions: HashMap<String, FunctionEntry>,
+    call_stack: Vec<CallFrame>,
+    io: AwkIO,
     pc: usize,
-    sp: usize,
+    argument_index: usize,
+    read_input_file: bool,
+    current_input: Option<String>,
+    // Where the next `print` goes; standard output when it is `None`.
+    output_stream: Option<String>,
+    max_call_depth: usize,
+    // `rand()` repeats the same sequence on every run until `srand()`
+    // picks another seed, as POSIX asks.
+    random: StdRng,
+    random_seed: f64,
+    // `exit` outside END records its status and runs the END actions, which
+    // start at `end_start`; the program then ends with that status.
+    end_start: usize,
+    exit_status: Option<i32>,
 }
 
+const DEFAULT_SUBSEP: &str = "\x1c";
+
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+// gawk's symbol table: `SYMTAB["x"]` names the global `x`. It always goes to
+// `environ`, so function locals can never be reached through it.
+const SYMTAB: &str = "SYMTAB";
+
 impl StackVM {
     pub fn new(program: Vec<Instruction>) -> Self {
+        let mut environ = HashMap::new();
+        environ.insert(
+            "SUBSEP".to_string(),
+            Some(Value::StringLiteral(DEFAULT_SUBSEP.to_string())),
+        );
+        environ.insert("FS".to_string(), Some(Value::StringLiteral(" ".to_string())));
+        environ.insert(
+            "CONVFMT".to_string(),
+            Some(Value::StringLiteral(DEFAULT_NUMBER_FORMAT.to_string())),
+        );
+        environ.insert(
+            "OFMT".to_string(),
+            Some(Value::StringLiteral(DEFAULT_NUMBER_FORMAT.to_string())),
+        );
+        environ.insert("NR".to_string(), Some(Value::Number(0)));
+        environ.insert("FNR".to_string(), Some(Value::Number(0)));
+        environ.insert("OFS".to_string(), Some(Value::StringLiteral(" ".to_string())));
+        environ.insert("ORS".to_string(), Some(Value::StringLiteral("\n".to_string())));
+
         StackVM {
             stack: Vec::new(),
             program,
             pc: 0,
-            sp: 0,
-            environ: HashMap::new(),
+            environ,
+            arrays: Vec::new(),
+            free_arrays: Vec::new(),
+            functions: HashMap::new(),
+            call_stack: Vec::new(),
+            io: AwkIO::new(),
+            argument_index: 1,
+            read_input_file: false,
+            current_input: None,
+            output_stream: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            random: StdRng::seed_from_u64(0),
+            random_seed: 0.0,
+            end_start: usize::MAX,
+            exit_status: None,
         }
     }
 
-    pub fn exec_add(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for ADD");
+    /// Tells the VM where the END actions begin, for `exit` to jump to.
+    pub fn set_end_start(&mut self, end_start: usize) {
+        self.end_start = end_start;
+    }
+
+    /// Limits how deeply user functions may nest, so runaway recursion ends
+    /// with an error instead of exhausting memory.
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
+
+    pub fn run(&mut self) {
+        while self.pc < self.program.len() {
+            let instruction = self.program[self.pc].clone();
+            self.pc += 1;
+            self.execute(instruction);
         }
+        self.io.close_outputs();
+        if let Some(status) = self.exit_status {
+            std::process::exit(status);
+        }
+    }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(left + right);
+    fn execute(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::PushValue(value) => self.stack.push(value),
+            Instruction::Pop => self.exec_pop(),
+            Instruction::FunctionCall(function_name, argument_names) => {
+                self.exec_function_call(&function_name, &argument_names)
+            }
+            Instruction::IndirectFunctionCall(argument_names) => {
+                self.exec_indirect_function_call(&argument_names)
+            }
+            Instruction::JumpIfFalse(target) => self.exec_jump_if_false(target),
+            Instruction::JumpIfTrue(target) => self.exec_jump_if_true(target),
+            Instruction::Jump(target) => self.exec_jump(target),
+            Instruction::Return => self.exec_return(),
+            Instruction::LoadVariable(variable_name) => self.exec_load_variable(&variable_name),
+            Instruction::LoadArgument(variable_name) => self.exec_load_argument(&variable_name),
+            Instruction::StoreVariable(variable_name) => {
+                self.execute_store_variable(variable_name)
+            }
+            Instruction::LoadAssociativeArrayValue(array_id) => {
+                self.execute_load_associative_array_value(&array_id)
+            }
+            Instruction::StoreAssociativeArrayValue(array_id) => {
+                self.execute_store_associative_array_value(&array_id)
+            }
+            Instruction::ForInNext(variable_name, exit_target) => {
+                self.exec_for_in_next(variable_name, exit_target)
+            }
+            Instruction::JoinSubscripts(subscript_count) => {
+                self.exec_join_subscripts(subscript_count)
+            }
+            Instruction::ArrayIn(array_id) => self.exec_array_in(&array_id),
+            Instruction::DeleteElement(array_id) => self.exec_delete_element(&array_id),
+            Instruction::DeleteArray(array_id) => self.exec_delete_array(&array_id),
+            Instruction::FieldRef => self.exec_field_ref(),
+            Instruction::SetField => self.exec_set_field(),
+            Instruction::Duplicate => self.exec_duplicate(),
+            Instruction::Swap => self.exec_swap(),
+            Instruction::Add => self.exec_add(),
+            Instruction::Sub => self.exec_sub(),
+            Instruction::Mul => self.exec_mul(),
+            Instruction::Div => self.execute_div(),
+            Instruction::Mod => self.execute_mod(),
+            Instruction::Exp => self.execute_exp(),
+            Instruction::Eq => self.execute_eq(),
+            Instruction::EreMatch => self.exec_ere_match(false),
+            Instruction::EreNonMatch => self.exec_ere_match(true),
+            Instruction::Ne => self.execute_ne(),
+            Instruction::Gt => self.execute_gt(),
+            Instruction::Ge => self.execute_ge(),
+            Instruction::Lt => self.execute_lt(),
+            Instruction::Le => self.execute_le(),
+            Instruction::Not => self.exec_not(),
+            Instruction::Incr => self.execute_incr(),
+            Instruction::Decr => self.execute_decr(),
+            Instruction::Pos => self.execute_pos(),
+            Instruction::Neg => self.execute_neg(),
+            Instruction::Concatenate => self.execute_concatenate(),
+            Instruction::Length => self.exec_length(),
+            Instruction::ToUpper => self.execute_toupper(),
+            Instruction::ToLower => self.execute_tolower(),
+            Instruction::IntFn => self.execute_int(),
+            Instruction::SinFn => self.exec_unary_builtin(Value::sine, "SIN"),
+            Instruction::CosFn => self.exec_unary_builtin(Value::cosine, "COS"),
+            Instruction::ExpFn => self.exec_unary_builtin(Value::exponential, "EXP"),
+            Instruction::LogFn => self.exec_unary_builtin(Value::logarithm, "LOG"),
+            Instruction::SqrtFn => self.exec_unary_builtin(Value::square_root, "SQRT"),
+            Instruction::Atan2Fn => self.exec_atan2(),
+            Instruction::RandFn => self.exec_rand(),
+            Instruction::MatchFn => self.exec_match(),
+            Instruction::SubFn => self.exec_substitute(false),
+            Instruction::GsubFn => self.exec_substitute(true),
+            Instruction::SrandFn(argument_count) => self.exec_srand(argument_count),
+            Instruction::AminFn => self.exec_unary_builtin(Value::array_min, "AMIN"),
+            Instruction::AmaxFn => self.exec_unary_builtin(Value::array_max, "AMAX"),
+            Instruction::Crc32Fn => self.exec_unary_builtin(Value::crc32, "CRC32"),
+            Instruction::SlurpFn => self.exec_slurp(),
+            Instruction::CommafyFn => self.exec_unary_builtin(Value::commafy, "COMMAFY"),
+            Instruction::ReverseFn(target) => self.exec_reverse(target.as_deref()),
+            Instruction::System => self.execute_system(),
+            Instruction::CloseStream => self.exec_close_stream(),
+            Instruction::FflushFn => self.exec_fflush(),
+            Instruction::Getline(target) => self.exec_getline(target),
+            Instruction::GetlineFile(target) => self.exec_getline_file(target),
+            Instruction::GetlineCommand(target) => self.exec_getline_command(target),
+            Instruction::OutputToFile => self.exec_select_output(OutputMode::Truncate),
+            Instruction::AppendToFile => self.exec_select_output(OutputMode::Append),
+            Instruction::OpenPipe => self.exec_select_output(OutputMode::Pipe),
+            Instruction::Split(array_id, argument_count) => {
+                self.exec_split(&array_id, argument_count)
+            }
+            Instruction::ParseKvFn(array_id, argument_count) => {
+                self.exec_parse_kv(&array_id, argument_count)
+            }
+            Instruction::ReadRecord(end_of_input) => self.exec_read_record(end_of_input),
+            Instruction::MatchRecord => self.exec_match_record(),
+            Instruction::NextFile => self.exec_next_file(),
+            Instruction::Print(argument_count) => self.exec_print(argument_count),
+            Instruction::Printf(argument_count) => self.exec_printf(argument_count),
+            Instruction::SprintfFn(argument_count) => self.exec_sprintf(argument_count),
+            Instruction::SubstrFn(argument_count) => self.exec_substr(argument_count),
+            Instruction::StripFn(argument_count) => self.exec_strip(argument_count),
+            Instruction::IndexOf => self.exec_index(),
+            Instruction::Exit => self.execute_exit(),
+        }
     }
 
-    pub fn exec_sub(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for SUB");
+    pub fn exec_pop(&mut self) {
+        if self.stack.pop().is_none() {
+            exit_err!("Not enough operands on the stack for POP");
+        }
+    }
+
+    fn pop_arguments(&mut self, argument_count: usize, instruction_name: &str) -> Vec<Value> {
+        if self.stack.len() < argument_count {
+            exit_err!("Not enough operands on the stack for {}", instruction_name);
         }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(left - right);
+        self.stack.split_off(self.stack.len() - argument_count)
     }
 
-    pub fn exec_mul(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for MUL");
+    fn special_variable(&self, variable_name: &str) -> String {
+        match self.environ.get(variable_name) {
+            Some(Some(value)) => value.to_string(),
+            _ => String::new(),
         }
+    }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(left * right);
+    // Writes to the stream chosen by a redirection, if any; the choice only
+    // lasts for one `print` or `printf`.
+    fn write_output(&mut self, output: &str) {
+        let stream = self.output_stream.take();
+        match self.io.write_to_output(stream.as_deref(), &string_bytes(output)) {
+            Ok(()) => {}
+            // The reader went away (`brawk ... | head -1`); nothing more
+            // can be printed, so stop quietly like other awks do.
+            Err(e) if e.kind() == ErrorKind::BrokenPipe && stream.is_none() => {
+                self.exit_on_broken_pipe()
+            }
+            // A command behind `print | "cmd"` may stop reading early.
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => {}
+            Err(e) => {
+                let stream = stream.as_deref().unwrap_or("standard output");
+                exit_err!("Error writing to `{}`: {}", stream, e);
+            }
+        }
     }
 
-    pub fn execute_div(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for DIV");
+    pub fn exec_select_output(&mut self, mode: OutputMode) {
+        let target = match self.stack.pop() {
+            Some(target) => target.to_string(),
+            None => {
+                exit_err!("Not enough operands on the stack for output redirection");
+            }
+        };
+
+        if mode == OutputMode::Pipe && !self.io.has_output(&target) {
+            self.flush_all();
+        }
+        if let Err(e) = self.io.open_output(&target, mode) {
+            exit_err!("cannot redirect to `{}`: {}", target, e);
         }
+        self.output_stream = Some(target);
+    }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
+    pub fn exec_print(&mut self, argument_count: usize) {
+        let arguments = self.pop_arguments(argument_count, "PRINT");
 
-        // Ensure that division by zero is handled
-        if right == 0 {
-            exit_err!("Division by zero");
+        let record = if arguments.is_empty() {
+            self.io.get_field(0)
+        } else {
+            let output_format = self.special_variable("OFMT");
+            arguments
+                .iter()
+                .map(|argument| argument.to_string_with_format(&output_format))
+                .collect::<Vec<String>>()
+                .join(&self.special_variable("OFS"))
+        };
+
+        let output = record + &self.special_variable("ORS");
+        self.write_output(&output);
+    }
+
+    pub fn exec_printf(&mut self, argument_count: usize) {
+        let arguments = self.pop_arguments(argument_count, "PRINTF");
+        let output = self.format_arguments(&arguments, "printf");
+        self.write_output(&output);
+    }
+
+    pub fn exec_sprintf(&mut self, argument_count: usize) {
+        let arguments = self.pop_arguments(argument_count, "SPRINTF");
+        let output = self.format_arguments(&arguments, "sprintf");
+        self.stack.push(Value::StringLiteral(output));
+    }
+
+    pub fn exec_substr(&mut self, argument_count: usize) {
+        let arguments = self.pop_arguments(argument_count, "SUBSTR");
+        let source = Value::StringLiteral(self.convert_to_string(&arguments[0]));
+        let start = arguments[1].to_number();
+        let length = arguments.get(2).map(Value::to_number);
+        match source.substring(start, length) {
+            Some(substring) => self.stack.push(substring),
+            None => {
+                exit_err!("Invalid operand for SUBSTR");
+            }
         }
+    }
 
-        self.stack.push(left / right);
+    /// `strip(s [, set])`: `s` without the characters of `set` (by default
+    /// whitespace) at either end.
+    pub fn exec_strip(&mut self, argument_count: usize) {
+        let arguments = self.pop_arguments(argument_count, "STRIP");
+        if !arguments.iter().all(Value::is_scalar) {
+            exit_err!("Invalid operand types for STRIP");
+        }
+        let source = Value::StringLiteral(self.convert_to_string(&arguments[0]));
+        let set = arguments.get(1).map(|set| Value::StringLiteral(self.convert_to_string(set)));
+        self.push_result(source.strip(set.as_ref()), "STRIP");
     }
 
-    pub fn execute_mod(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for MOD");
+    pub fn exec_index(&mut self) {
+        let arguments = self.pop_arguments(2, "INDEX");
+        let source = Value::StringLiteral(self.convert_to_string(&arguments[0]));
+        let pattern = Value::StringLiteral(self.convert_to_string(&arguments[1]));
+        match source.index_of(&pattern) {
+            Some(position) => self.stack.push(position),
+            None => {
+                exit_err!("Invalid operands for INDEX");
+            }
         }
+    }
+
+    // `printf` and `sprintf` format identically; only where the text goes
+    // differs.
+    fn format_arguments(&self, arguments: &[Value], function_name: &str) -> String {
+        let format_string = match arguments.first() {
+            Some(format_string) => format_string.to_string(),
+            None => {
+                exit_err!("{} requires a format string", function_name);
+            }
+        };
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
+        let conversion_format = self.special_variable("CONVFMT");
+        format_with_conversion(&format_string, &arguments[1..], &conversion_format)
+    }
 
-        if right == 0 {
-            exit_err!("Modulo by zero");
+    /// Sets a global before the program runs, as `-v` and `-F` do. An
+    /// `ArrayLiteral`, such as ARGV, becomes an array.
+    pub fn set_variable(&mut self, variable_name: &str, value: Value) {
+        let value = match value {
+            Value::ArrayLiteral(elements) => Value::Array(self.new_array(elements)),
+            scalar => scalar,
+        };
+        self.environ.insert(variable_name.to_string(), Some(value));
+    }
+
+    /// Opens the next input named in ARGV and returns its key in `io`, or
+    /// None once ARGV is exhausted. `var=value` operands are assigned as
+    /// they are reached, so they only affect the files after them; standard
+    /// input is read when ARGV names no files at all.
+    fn open_next_input(&mut self) -> Option<String> {
+        loop {
+            let argument_count = match self.lookup_variable("ARGC") {
+                Some(Some(argc)) => argc.to_number() as usize,
+                _ => 0,
+            };
+
+            if self.argument_index >= argument_count {
+                if self.read_input_file {
+                    return None;
+                }
+                self.read_input_file = true;
+                let input = self.open_input("-");
+                // gawk leaves FILENAME empty for implicit standard input.
+                self.set_variable("FILENAME", Value::StringLiteral(String::new()));
+                return input;
+            }
+
+            let operand = self
+                .array("ARGV")
+                .and_then(|argv| argv.get(&self.argument_index.to_string()))
+                .map(|operand| operand.to_string())
+                .unwrap_or_default();
+            self.argument_index += 1;
+
+            if operand.is_empty() {
+                continue;
+            }
+
+            if let Some((name, value)) = parse_assignment(&operand) {
+                self.assign_variable(name, Value::from_input(value));
+                continue;
+            }
+
+            self.read_input_file = true;
+            if let Some(input) = self.open_input(&operand) {
+                return Some(input);
+            }
+        }
+    }
+
+    fn open_input(&mut self, file_path: &str) -> Option<String> {
+        if let Err(e) = self.io.open_main_input(file_path) {
+            eprintln!("{}: cannot open `{}`: {}", PROG_NAME, file_path, e);
+            return None;
         }
 
-        self.stack.push(left % right);
+        self.environ.insert(
+            "FILENAME".to_string(),
+            Some(Value::StringLiteral(file_path.to_string())),
+        );
+        Some(file_path.to_string())
     }
 
-    pub fn execute_exp(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for EXP");
+    pub fn define_function(&mut self, name: &str, entry: usize, parameters: Vec<String>) {
+        self.functions
+            .insert(name.to_string(), FunctionEntry { entry, parameters });
+    }
+
+    fn lookup_variable(&self, variable_name: &str) -> Option<&Option<Value>> {
+        if let Some(frame) = self.call_stack.last() {
+            if let Some(value) = frame.locals.get(variable_name) {
+                return Some(value);
+            }
         }
 
-        let (base, exponent) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(base.pow(exponent));
+        self.environ.get(variable_name)
     }
 
-    pub fn execute_shr(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for SHR");
+    fn assign_variable(&mut self, variable_name: String, value: Value) {
+        if let Some(frame) = self.call_stack.last_mut() {
+            if let Some(local) = frame.locals.get_mut(&variable_name) {
+                *local = Some(value);
+                return;
+            }
         }
 
-        let (value, shift) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(value >> shift);
+        // NF is not kept in `environ`; it is read from the record.
+        if variable_name == "NF" {
+            let count = value.to_number();
+            if count < 0.0 {
+                exit_err!("NF cannot be set to {}", count);
+            }
+            let output_separator = self.special_variable("OFS");
+            self.io.set_field_count(count as usize, &output_separator);
+            return;
+        }
+        self.environ.insert(variable_name, Some(value));
     }
 
-    pub fn execute_shl(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for SHL");
+    pub fn exec_function_call(&mut self, function_name: &str, argument_names: &[Option<String>]) {
+        let argument_count = argument_names.len();
+        let function = match self.functions.get(function_name) {
+            Some(function) => function.clone(),
+            None => {
+                exit_err!("function `{}` not defined", function_name);
+            }
+        };
+
+        if argument_count > function.parameters.len() {
+            exit_err!(
+                "function `{}` called with {} arguments, accepts only {}",
+                function_name,
+                argument_count,
+                function.parameters.len()
+            );
+        }
+
+        if self.stack.len() < argument_count {
+            exit_err!("Not enough operands on the stack for FUNCTION_CALL");
         }
 
-        let (value, shift) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(value << shift);
+        if self.call_stack.len() >= self.max_call_depth {
+            exit_err!(
+                "call stack exhausted calling `{}` ({} nested calls)",
+                function_name,
+                self.max_call_depth
+            );
+        }
+
+        let mut arguments = self.stack.split_off(self.stack.len() - argument_count).into_iter();
+        let mut array_sources = HashMap::new();
+        let locals = function
+            .parameters
+            .iter()
+            .zip(argument_names.iter().map(Some).chain(std::iter::repeat(None)))
+            .map(|(parameter, argument_name)| {
+                let argument = arguments.next();
+                if let (Some(Value::Uninitialized), Some(Some(name))) = (&argument, argument_name) {
+                    array_sources.insert(parameter.clone(), name.clone());
+                }
+                (parameter.clone(), argument)
+            })
+            .collect();
+
+        self.call_stack.push(CallFrame {
+            return_pc: self.pc,
+            locals,
+            local_arrays: Vec::new(),
+            stack_height: self.stack.len(),
+            array_sources,
+        });
+        self.pc = function.entry;
     }
 
-    pub fn execute_eq(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for EQ");
+    pub fn exec_indirect_function_call(&mut self, argument_names: &[Option<String>]) {
+        let argument_count = argument_names.len();
+        let function_name = match self.stack.pop() {
+            Some(function_name) => function_name.to_string(),
+            None => {
+                exit_err!("Not enough operands on the stack for INDIRECT_FUNCTION_CALL");
+            }
+        };
+
+        if self.functions.contains_key(&function_name) {
+            return self.exec_function_call(&function_name, argument_names);
         }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(Value::Bool(left == right));
+        match Instruction::unary_builtin(&function_name) {
+            Some(builtin) if argument_count == 1 => self.execute(builtin),
+            Some(_) => {
+                exit_err!(
+                    "builtin `{}` called indirectly with {} arguments, expects 1",
+                    function_name,
+                    argument_count
+                );
+            }
+            None => {
+                exit_err!("function `{}` not defined", function_name);
+            }
+        }
     }
 
-    pub fn execute_ne(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for NE");
+    pub fn exec_return(&mut self) {
+        let frame = match self.call_stack.pop() {
+            Some(frame) => frame,
+            None => {
+                exit_err!("RETURN executed outside of a function call");
+            }
+        };
+
+        let return_value = match self.stack.pop() {
+            Some(Value::Array(_)) => {
+                exit_err!("an array cannot be returned from a function");
+            }
+            Some(value) => value,
+            None => {
+                exit_err!("Not enough operands on the stack for RETURN");
+            }
+        };
+
+        self.stack.truncate(frame.stack_height);
+        for handle in frame.local_arrays {
+            self.arrays[handle].clear();
+            self.free_arrays.push(handle);
         }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(Value::Bool(left != right));
+        self.stack.push(return_value);
+        self.pc = frame.return_pc;
     }
 
-    pub fn execute_gt(&mut self) {
+    fn pop_operands(&mut self, instruction_name: &str) -> (Value, Value) {
         if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for GT");
+            exit_err!("Not enough operands on the stack for {}", instruction_name);
         }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(Value::Bool(left > right));
+        let right = self.stack.pop().unwrap();
+        let left = self.stack.pop().unwrap();
+        (left, right)
     }
 
-    pub fn execute_ge(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for GE");
+    fn pop_numeric_operands(&mut self, instruction_name: &str) -> (Value, Value) {
+        let (left, right) = self.pop_operands(instruction_name);
+        let (left, right) = (numeric_operand(left), numeric_operand(right));
+
+        if matches!(left, Value::Float(_)) || matches!(right, Value::Float(_)) {
+            (Value::Float(left.to_number()), Value::Float(right.to_number()))
+        } else {
+            (left, right)
         }
+    }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(Value::Bool(left >= right));
+    fn push_result(&mut self, result: Option<Value>, instruction_name: &str) {
+        match result {
+            Some(result) => self.stack.push(result),
+            None => {
+                exit_err!("Invalid operand types for {}", instruction_name);
+            }
+        }
     }
 
-    pub fn execute_lt(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for LT");
+    pub fn exec_add(&mut self) {
+        let (left, right) = self.pop_numeric_operands("ADD");
+        self.push_result(left.add(&right), "ADD");
+    }
+
+    pub fn exec_sub(&mut self) {
+        let (left, right) = self.pop_numeric_operands("SUB");
+        self.push_result(left.subtract(&right), "SUB");
+    }
+
+    pub fn exec_mul(&mut self) {
+        let (left, right) = self.pop_numeric_operands("MUL");
+        self.push_result(left.multiply(&right), "MUL");
+    }
+
+    pub fn execute_div(&mut self) {
+        let (left, right) = self.pop_numeric_operands("DIV");
+
+        // Ensure that division by zero is handled
+        if right.to_number() == 0.0 {
+            exit_err!("Division by zero");
         }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(Value::Bool(left < right));
+        let quotient = match (&left, &right) {
+            (Value::Number(a), Value::Number(b)) if a.checked_rem(*b) == Some(0) => {
+                left.divide(&right)
+            }
+            _ => Some(Value::Float(left.to_number() / right.to_number())),
+        };
+        self.push_result(quotient, "DIV");
     }
 
-    pub fn execute_le(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for LE");
+    pub fn execute_mod(&mut self) {
+        let (left, right) = self.pop_numeric_operands("MOD");
+
+        if right.to_number() == 0.0 {
+            exit_err!("Modulo by zero");
         }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(Value::Bool(left <= right));
+        self.push_result(left.modulo(&right), "MOD");
     }
 
-    pub fn execute_and(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for AND");
+    pub fn execute_exp(&mut self) {
+        let (base, exponent) = self.pop_numeric_operands("EXP");
+        self.push_result(base.exponentiate(&exponent), "EXP");
+    }
+
+    pub fn execute_eq(&mut self) {
+        let (left, right) = self.pop_operands("EQ");
+        self.push_result(left.equals(&right), "EQ");
+    }
+
+    /// `~` and `!~`. A right operand that isn't a regex literal is a dynamic
+    /// regex: its string value is the pattern.
+    pub fn exec_ere_match(&mut self, negated: bool) {
+        let instruction_name = if negated { "ERE_NON_MATCH" } else { "ERE_MATCH" };
+        let (left, right) = self.pop_operands(instruction_name);
+        let input = Value::StringLiteral(self.convert_to_string(&left));
+        let pattern = match right {
+            Value::RegexPattern(pattern) => pattern,
+            other => self.convert_to_string(&other),
+        };
+
+        if let Err(e) = get_or_compile(&pattern) {
+            exit_err!("Invalid regular expression `{}`: {}", pattern, e);
         }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(Value::Bool(left & right));
+        let pattern = Value::RegexPattern(pattern);
+        let result = if negated {
+            input.ere_non_match(&pattern)
+        } else {
+            input.ere_match(&pattern)
+        };
+        self.push_result(result, instruction_name);
     }
 
-    pub fn execute_or(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for OR");
+    pub fn execute_ne(&mut self) {
+        let (left, right) = self.pop_operands("NE");
+        self.push_result(left.not_equals(&right), "NE");
+    }
+
+    pub fn execute_gt(&mut self) {
+        let (left, right) = self.pop_operands("GT");
+        self.push_result(left.greater_than(&right), "GT");
+    }
+
+    pub fn execute_ge(&mut self) {
+        let (left, right) = self.pop_operands("GE");
+        self.push_result(left.greater_than_equals(&right), "GE");
+    }
+
+    pub fn execute_lt(&mut self) {
+        let (left, right) = self.pop_operands("LT");
+        self.push_result(left.less_than(&right), "LT");
+    }
+
+    pub fn execute_le(&mut self) {
+        let (left, right) = self.pop_operands("LE");
+        self.push_result(left.less_than_equals(&right), "LE");
+    }
+
+    pub fn exec_not(&mut self) {
+        match self.stack.pop() {
+            Some(operand) => self.stack.push(Value::Bool(operand.is_falsy())),
+            None => {
+                exit_err!("Not enough operands on the stack for NOT");
+            }
         }
+    }
 
-        let (left, right) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-        self.stack.push(Value::Bool(left | right));
+    fn pop_numeric_operand(&mut self, instruction_name: &str) -> Value {
+        match self.stack.pop() {
+            Some(operand) => numeric_operand(operand),
+            None => {
+                exit_err!("Not enough operands on the stack for {}", instruction_name);
+            }
+        }
     }
 
     pub fn execute_incr(&mut self) {
-        if self.stack.len() < 1 {
-            exit_err!("Not enough operands on the stack for INCR");
+        let mut operand = self.pop_numeric_operand("INCR");
+        if operand.increment().is_none() {
+            operand = Value::Float(operand.to_number() + 1.0);
         }
-
-        let operand = self.stack.pop();
-        self.stack.push(operand.increment());
+        self.stack.push(operand);
     }
 
     pub fn execute_decr(&mut self) {
-        if self.stack.len() < 1 {
-            exit_err!("Not enough operands on the stack for INCR");
+        let mut operand = self.pop_numeric_operand("DECR");
+        if operand.decrement().is_none() {
+            operand = Value::Float(operand.to_number() - 1.0);
         }
-
-        let operand = self.stack.pop();
-        self.stack.push(operand.decerement());
+        self.stack.push(operand);
     }
 
     pub fn execute_pos(&mut self) {
-        if self.stack.len() < 1 {
-            exit_err!("Not enough operands on the stack for INCR");
+        let operand = self.pop_numeric_operand("POS");
+        self.stack.push(operand);
+    }
+
+    pub fn execute_neg(&mut self) {
+        let operand = self.pop_numeric_operand("NEG");
+        self.stack.push(-operand);
+    }
+
+    pub fn execute_concatenate(&mut self) {
+        if self.stack.len() < 2 {
+            exit_err!("Not enough operands on the stack for CONCATENATE");
         }
 
-        let operand = self.stack.pop();
-        self.stack.push(operand);
+        let right = self.stack.pop().unwrap();
+        let left = self.stack.pop().unwrap();
+        let concatenated = self.convert_to_string(&left) + &self.convert_to_string(&right);
+        self.stack.push(Value::StringLiteral(concatenated));
+    }
 
+    /// Reads the next record into $0, moving through the inputs in ARGV,
+    /// and jumps to `end_of_input` once they are all exhausted.
+    pub fn exec_read_record(&mut self, end_of_input: usize) {
+        // Each record starts from an empty stack; `next` may have left a
+        // loop's keys on it.
+        self.stack.clear();
+        match self.read_main_input() {
+            Some(line) => self.set_record(line),
+            None => self.pc = end_of_input,
+        }
     }
 
-    pub fn execute_neg(&mut self) {
-        if self.stack.len() < 1 {
-            exit_err!("Not enough operands on the stack for INCR");
+    // Reads the next line of the main input, moving through the ARGV operands
+    // as each runs out, and counts it in NR and FNR.
+    fn read_main_input(&mut self) -> Option<String> {
+        loop {
+            let input = match self.current_input.clone() {
+                Some(input) => input,
+                None => {
+                    let input = self.open_next_input()?;
+                    self.environ.insert("FNR".to_string(), Some(Value::Number(0)));
+                    self.current_input = Some(input.clone());
+                    input
+                }
+            };
+
+            let mut line = String::new();
+            match self.io.read_main_input(&mut line) {
+                Ok(0) => {
+                    self.io.close_main_input();
+                    self.current_input = None;
+                }
+                Ok(_) => {
+                    self.increment_counter("NR");
+                    self.increment_counter("FNR");
+                    if line.ends_with('\n') {
+                        line.pop();
+                    }
+                    return Some(line);
+                }
+                Err(e) => {
+                    exit_err!("Error reading `{}`: {}", input, e);
+                }
+            }
         }
+    }
 
-        let operand = self.stack.pop();
-        self.stack.push(-operand);
+    fn set_record(&mut self, line: String) {
+        let field_separator = self.special_variable("FS");
+        self.io.set_record(line, &field_separator);
+    }
+
+    fn deliver_line(&mut self, line: String, target: GetlineTarget) {
+        match target {
+            GetlineTarget::Record => self.set_record(line),
+            GetlineTarget::Stack => self.stack.push(Value::from_input(line)),
+        }
+    }
+
+    /// Plain `getline`: the next main-input record, or 0 once the input is
+    /// exhausted (always the case in END).
+    pub fn exec_getline(&mut self, target: GetlineTarget) {
+        let status = match self.read_main_input() {
+            Some(line) => {
+                self.deliver_line(line, target);
+                1
+            }
+            None => 0,
+        };
+        self.stack.push(Value::Number(status));
+    }
+
+    /// `getline < file`: the next line of a file kept open until `close()`.
+    /// It leaves NR and FNR alone and pushes -1 if the file can't be read.
+    pub fn exec_getline_file(&mut self, target: GetlineTarget) {
+        let file_path = match self.stack.pop() {
+            Some(file_path) => file_path.to_string(),
+            None => {
+                exit_err!("Not enough operands on the stack for GETLINE_FILE");
+            }
+        };
+
+        if !self.io.has_input(&file_path) && self.io.add_input(&file_path).is_err() {
+            self.stack.push(Value::Number(-1));
+            return;
+        }
+
+        let status = self.getline_from(&file_path, target);
+        self.stack.push(Value::Number(status));
+    }
+
+    /// `cmd | getline`: the next line of a command's output, the command
+    /// running until `close()`. Each line read counts in NR.
+    pub fn exec_getline_command(&mut self, target: GetlineTarget) {
+        let command_line = match self.stack.pop() {
+            Some(command_line) => command_line.to_string(),
+            None => {
+                exit_err!("Not enough operands on the stack for GETLINE_COMMAND");
+            }
+        };
+
+        if !self.io.has_input(&command_line) {
+            self.flush_all();
+            if self.io.add_command_input(&command_line).is_err() {
+                self.stack.push(Value::Number(-1));
+                return;
+            }
+        }
 
+        let status = self.getline_from(&command_line, target);
+        if status > 0 {
+            self.increment_counter("NR");
+        }
+        self.stack.push(Value::Number(status));
+    }
 
+    fn getline_from(&mut self, input: &str, target: GetlineTarget) -> i64 {
+        let mut line = String::new();
+        match self.io.read_from_input(input, &mut line) {
+            Ok(0) => 0,
+            Ok(_) => {
+                if line.ends_with('\n') {
+                    line.pop();
+                }
+                self.deliver_line(line, target);
+                1
+            }
+            Err(_) => -1,
+        }
     }
 
-    pub fn execute_begin(&mut self) {
-        // TODO: Implement BEGIN
+    /// Abandons the current input file; the next READ_RECORD opens the one
+    /// after it.
+    pub fn exec_next_file(&mut self) {
+        if self.current_input.take().is_some() {
+            self.io.close_main_input();
+        }
     }
 
-    pub fn execute_end(&mut self) {
-        // TODO: Implement END
+    fn increment_counter(&mut self, variable_name: &str) {
+        let count = match self.environ.get(variable_name) {
+            Some(Some(count)) => count.to_number() as i64,
+            _ => 0,
+        };
+        self.environ
+            .insert(variable_name.to_string(), Some(Value::Number(count + 1)));
+    }
+
+    pub fn exec_match_record(&mut self) {
+        let record = self.io.get_field(0);
+        match self.stack.pop() {
+            Some(pattern) => self.push_result(pattern.match_record(&record), "MATCH_RECORD"),
+            None => {
+                exit_err!("Not enough operands on the stack for MATCH_RECORD");
+            }
+        }
     }
 
     pub fn execute_exit(&mut self) {
-        if self.stack.len() > 0 {
-            let exit_reason = self.stack.pop().unwrap();
-            exit_reason.exit();
-        } else {
-            std::process::exit(1);
+        let status = match self.stack.pop() {
+            Some(Value::Uninitialized) => self.exit_status.unwrap_or(0),
+            Some(status) => status.exit_code(),
+            None => {
+                exit_err!("Not enough operands on the stack for EXIT");
+            }
+        };
+
+        // Inside a function, the call made from the outermost rule decides
+        // whether this is an END action.
+        let rule_pc = self.call_stack.first().map_or(self.pc, |frame| frame.return_pc);
+        if rule_pc > self.end_start || self.exit_status.is_some() {
+            self.io.close_outputs();
+            std::process::exit(status);
+        }
+
+        self.exit_status = Some(status);
+        self.call_stack.clear();
+        self.stack.clear();
+        self.pc = self.end_start;
+    }
+
+    pub fn exec_jump_if_false(&mut self, target: usize) {
+        if !self.pop_condition("JUMP_IF_FALSE") {
+            self.pc = target;
+        }
+    }
+
+    pub fn exec_jump_if_true(&mut self, target: usize) {
+        if self.pop_condition("JUMP_IF_TRUE") {
+            self.pc = target;
+        }
+    }
+
+    // Conditions are scalars tested for truthiness. Anything else on the
+    // stack means the compiler emitted a bad sequence, so it is reported
+    // rather than treated as false.
+    fn pop_condition(&mut self, instruction_name: &str) -> bool {
+        match self.stack.pop() {
+            Some(condition) if condition.is_scalar() => condition.is_truthy(),
+            Some(Value::Array(_) | Value::ArrayLiteral(_)) => {
+                exit_err!("An array cannot be used as a condition in {}", instruction_name);
+            }
+            Some(other) => {
+                exit_err!("Invalid condition {:?} for {}", other, instruction_name);
+            }
+            None => {
+                exit_err!("Not enough operands on the stack for {}", instruction_name);
+            }
+        }
+    }
+
+    pub fn exec_jump(&mut self, target: usize) {
+        self.pc = target;
+    }
+
+    pub fn exec_load_variable(&mut self, variable_name: &str) {
+        if let Some(Some(Value::Array(_))) = self.lookup_variable(variable_name) {
+            exit_err!("array `{}` cannot be used as a scalar", variable_name);
         }
+        self.exec_load_argument(variable_name);
     }
 
-    pub fn exec_jump_if_false(&mut self) {
-        if let Some(Value::Instruction(target)) = self.stack.pop() {
-            if let Some(Value::Bool(false)) = self.stack.pop() {
-                self.sp = target;
+    /// Loads a name passed to a function or builtin, which may be an array.
+    pub fn exec_load_argument(&mut self, variable_name: &str) {
+        // NF is derived from the current record, which is split lazily.
+        if variable_name == "NF" {
+            let field_count = self.io.field_count();
+            self.stack.push(Value::Number(field_count as i64));
+            return;
+        }
+
+        let value = match self.lookup_variable(variable_name) {
+            Some(Some(value)) => value.clone(),
+            _ => Value::Uninitialized,
+        };
+        self.stack.push(value);
+    }
+
+    // Arrays are not values: neither side of an assignment may be one.
+    pub fn execute_store_variable(&mut self, variable_name: String) {
+        let target_is_array =
+            matches!(self.lookup_variable(&variable_name), Some(Some(Value::Array(_))));
+        match self.stack.pop() {
+            Some(Value::Array(_)) => {
+                exit_err!("an array cannot be assigned to `{}`", variable_name);
+            }
+            Some(_) if target_is_array => {
+                exit_err!("array `{}` cannot be assigned to", variable_name);
+            }
+            Some(value_to_store) => self.assign_variable(variable_name, value_to_store),
+            None => {
+                exit_err!("Not enough operands on the stack for STORE_VARIABLE");
             }
         }
     }
 
-    pub fn exec_jump_if_true(&mut self) {
-        if let Some(Value::Instruction(target)) = self.stack.pop() {
-            if let Some(Value::Bool(true)) = self.stack.pop() {
-                self.sp = target;
+    fn new_array(&mut self, elements: HashMap<String, Box<Value>>) -> usize {
+        match self.free_arrays.pop() {
+            Some(handle) => {
+                self.arrays[handle] = elements;
+                handle
+            }
+            None => {
+                self.arrays.push(elements);
+                self.arrays.len() - 1
             }
         }
     }
 
-    pub fn exec_jump(&mut self) {
-        if let Some(Value::Instruction(target)) = self.stack.pop() {
-            self.sp = target;
+    fn array(&self, array_id: &str) -> Option<&HashMap<String, Box<Value>>> {
+        match self.lookup_variable(array_id) {
+            Some(Some(Value::Array(handle))) => Some(&self.arrays[*handle]),
+            Some(None | Some(Value::Uninitialized)) | None => None,
+            Some(Some(_)) => {
+                exit_err!("scalar `{}` cannot be used as an array", array_id);
+            }
         }
     }
 
-    pub fn exec_load_variable(&mut self) {
-        if let Some(Value::Identifier(variable_name)) = self.stack.pop() {
-            if let Some(value) = self.environ.get(&variable_name) {
-                self.stack.push(value.as_ref().unwrap().clone());
-            } else {
-                exit_err!("Error: variable `{}` not found", variable_name);
+    // Creates the array on first use, in the function's frame when
+    // `array_id` is one of its parameters.
+    fn array_mut(&mut self, array_id: &str) -> &mut HashMap<String, Box<Value>> {
+        let handle = match self.lookup_variable(array_id) {
+            Some(Some(Value::Array(handle))) => *handle,
+            Some(None | Some(Value::Uninitialized)) | None => {
+                let handle = self.new_array(HashMap::new());
+                self.bind_new_array(array_id, handle);
+                handle
             }
-        } else {
-            exit_err!("Invalid operand type for LoadVariable");
+            Some(Some(_)) => {
+                exit_err!("scalar `{}` cannot be used as an array", array_id);
+            }
+        };
+        &mut self.arrays[handle]
+    }
+
+    // A parameter passed an untyped variable shares the new array with the
+    // caller, up to the scope that owns the variable and so frees the array.
+    fn bind_new_array(&mut self, array_id: &str, handle: usize) {
+        let mut name = array_id.to_string();
+        for frame in self.call_stack.iter_mut().rev() {
+            if !frame.locals.contains_key(&name) {
+                break;
+            }
+            frame.locals.insert(name.clone(), Some(Value::Array(handle)));
+            match frame.array_sources.get(&name) {
+                Some(source) => name = source.clone(),
+                None => {
+                    frame.local_arrays.push(handle);
+                    return;
+                }
+            }
+        }
+        self.environ.insert(name, Some(Value::Array(handle)));
+    }
+
+    // Builtins that read a whole array, such as `length` and `amin`, see a
+    // copy of its elements.
+    fn array_contents(&self, value: Value) -> Value {
+        match value {
+            Value::Array(handle) => Value::ArrayLiteral(self.arrays[handle].clone()),
+            value => value,
         }
     }
 
-    pub fn execute_store_variable(&mut self) {
+    // Numbers become strings through CONVFMT everywhere except output, where
+    // `print` uses OFMT.
+    fn convert_to_string(&self, value: &Value) -> String {
+        value.to_string_with_format(&self.special_variable("CONVFMT"))
+    }
+
+    fn subscript_key(&self, subscript: &Value) -> String {
+        subscript.to_subscript_key(&self.special_variable("CONVFMT"))
+    }
+
+    fn pop_subscript(&mut self, instruction_name: &str) -> String {
+        match self.stack.pop() {
+            Some(subscript) => self.subscript_key(&subscript),
+            None => {
+                exit_err!("Not enough operands on the stack for {}", instruction_name);
+            }
+        }
+    }
+
+    pub fn execute_load_associative_array_value(&mut self, array_id: &str) {
+        let idx = self.pop_subscript("LOAD_ASSOCIATIVE_ARRAY_VALUE");
+        if array_id == SYMTAB {
+            let value = match self.environ.get(&idx) {
+                Some(Some(value)) => value.clone(),
+                _ => Value::Uninitialized,
+            };
+            self.stack.push(value);
+            return;
+        }
+
+        let value = self
+            .array_mut(array_id)
+            .entry(idx)
+            .or_insert_with(|| Box::new(Value::Uninitialized))
+            .clone();
+        self.stack.push(*value);
+    }
+
+    pub fn execute_store_associative_array_value(&mut self, array_id: &str) {
         if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for STORE_VARIABLE");
+            exit_err!("Not enough operands on the stack for STORE_ASSOCIATIVE_ARRAY_VALUE");
         }
 
-        if let (Some(Value::Identifier(variable_name)), Some(value_to_store)) =
-            (self.stack.pop().unwrap(), self.stack.pop().unwrap())
-        {
-            self.environ.insert(variable_name, Some(value_to_store));
-        } else {
-            exit_err!("Invalid operand types for STORE_VARIABLE");
+        let idx = self.pop_subscript("STORE_ASSOCIATIVE_ARRAY_VALUE");
+        let value_to_store = self.stack.pop().unwrap();
+        if let Value::Array(_) = value_to_store {
+            exit_err!("an array cannot be stored in `{}[{}]`", array_id, idx);
+        }
+        if array_id == SYMTAB {
+            self.environ.insert(idx, Some(value_to_store));
+            return;
         }
+
+        self.array_mut(array_id).insert(idx, Box::new(value_to_store));
+    }
+
+    pub fn exec_delete_element(&mut self, array_id: &str) {
+        let idx = self.pop_subscript("DELETE_ELEMENT");
+        self.array_mut(array_id).remove(&idx);
     }
 
-    pub fn execute_load_associative_array_value(&mut self) {
-        if self.stack.is_empty() {
-            exit_err!("Not enough operands on the stack for LOAD_ASSOCIATIVE_ARRAY_VALUE");
+    pub fn exec_delete_array(&mut self, array_id: &str) {
+        self.array_mut(array_id).clear();
+    }
+
+    pub fn exec_field_ref(&mut self) {
+        let index = match self.stack.pop() {
+            Some(index) => index.to_number(),
+            None => {
+                exit_err!("Not enough operands on the stack for FIELD_REF");
+            }
+        };
+
+        if index < 0.0 {
+            exit_err!("Attempt to access field {}", index);
         }
 
-        if let Some(Value::AssociativeIdentifier(ref array_id, ref idx)) = self.stack.pop() {
-            let mut key = array_id.clone();
-            key.push_str(idx);
+        let field = self.io.get_field(index as usize);
+        self.stack.push(Value::from_input(field));
+    }
 
-            if let Some(value) = self.environ.get(&key) {
-                self.stack.push(value.as_ref().unwrap().clone());
-            } else {
-                exit_err!(
-                    "Error: either array `{}` or index `{}` don't exit, array_id",
-                    array_id,
-                    idx
-                );
+    /// Assigning a field marks the record dirty, so `$0` is rejoined with
+    /// OFS the next time it is read; assigning `$0` re-splits it instead.
+    pub fn exec_set_field(&mut self) {
+        let (index, value) = match (self.stack.pop(), self.stack.pop()) {
+            (Some(index), Some(value)) => (index.to_number(), value),
+            _ => {
+                exit_err!("Not enough operands on the stack for SET_FIELD");
             }
+        };
+
+        if index < 0.0 {
+            exit_err!("Attempt to assign field {}", index);
+        }
+
+        let text = value.to_string_with_format(&self.special_variable("CONVFMT"));
+        if index as usize == 0 {
+            self.set_record(text);
         } else {
-            exit_err!("Invalid operand type for LOAD_ASSOCIATIVE_ARRAY_VALUE");
+            let output_separator = self.special_variable("OFS");
+            self.io.set_field(index as usize, &text, &output_separator);
         }
+    }
 
-        self.sp += 1;
+    fn exec_unary_builtin(&mut self, builtin: fn(&Value) -> Option<Value>, instruction_name: &str) {
+        let result = match self.stack.pop() {
+            Some(value) => builtin(&self.array_contents(value)),
+            None => {
+                exit_err!("Not enough operands on the stack for {}", instruction_name);
+            }
+        };
+        self.push_result(result, instruction_name);
     }
 
-    pub fn execute_store_associative_array_value(&mut self) {
-        if self.stack.len() < 2 {
-            exit_err!("Not enough operands on the stack for STORE_ASSOCIATIVE_ARRAY_VALUE");
+    pub fn exec_length(&mut self) {
+        let result = match self.stack.pop() {
+            Some(Value::Array(handle)) => Some(Value::Number(self.arrays[handle].len() as i64)),
+            Some(value) => value.length(&self.special_variable("CONVFMT")),
+            None => {
+                exit_err!("Not enough operands on the stack for LENGTH");
+            }
+        };
+        self.push_result(result, "LENGTH");
+    }
+
+    /// `slurp(file)`: the whole file as one string. A file that can't be
+    /// read gives the empty string and sets ERRNO to the reason.
+    pub fn exec_slurp(&mut self) {
+        self.exec_unary_builtin(Value::slurp, "SLURP");
+        if let Some(message) = take_last_error() {
+            self.environ.insert("ERRNO".to_string(), Some(Value::StringLiteral(message)));
+        }
+    }
+
+    /// `reverse(s)` reverses a string. `reverse(a, b)` fills `b` with the
+    /// elements 1..n of `a` in reverse order, leaving `a` alone, and pushes n.
+    pub fn exec_reverse(&mut self, target: Option<&str>) {
+        let handle = match (self.stack.pop(), target) {
+            (Some(Value::Array(handle)), Some(_)) => Some(handle),
+            (Some(Value::Uninitialized), Some(_)) => None,
+            (Some(Value::Array(_)), None) => {
+                exit_err!("reverse() needs a second array to reverse an array into");
+            }
+            (Some(value), None) => {
+                let reversed = Value::StringLiteral(self.convert_to_string(&value)).reverse();
+                return self.push_result(reversed, "REVERSE");
+            }
+            (Some(_), Some(_)) => {
+                exit_err!("reverse() needs an array to reverse into another array");
+            }
+            (None, _) => {
+                exit_err!("Not enough operands on the stack for REVERSE");
+            }
+        };
+
+        let elements = handle.map(|handle| self.arrays[handle].clone()).unwrap_or_default();
+        let count =
+            (1..).take_while(|index: &usize| elements.contains_key(&index.to_string())).count();
+        let reversed: HashMap<String, Box<Value>> = (1..=count)
+            .map(|index| (index.to_string(), elements[&(count + 1 - index).to_string()].clone()))
+            .collect();
+
+        // Only a target gets this far.
+        *self.array_mut(target.unwrap()) = reversed;
+        self.stack.push(Value::Number(count as i64));
+    }
+
+    pub fn exec_atan2(&mut self) {
+        let (y, x) = self.pop_operands("ATAN2");
+        self.push_result(y.arctangent(&x), "ATAN2");
+    }
+
+    /// `rand()`: a random number in [0, 1).
+    pub fn exec_rand(&mut self) {
+        let number = self.random.gen_range(0.0..1.0);
+        self.stack.push(Value::Float(number));
+    }
+
+    /// `srand([seed])` reseeds `rand()`, with the time of day when no seed
+    /// is given, and pushes the previous seed.
+    pub fn exec_srand(&mut self, argument_count: usize) {
+        let seed = match self.pop_arguments(argument_count, "SRAND").first() {
+            Some(seed) => seed.to_number(),
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |time| time.as_secs() as f64),
+        };
+        self.random = StdRng::seed_from_u64(seed as u64);
+        let previous_seed = std::mem::replace(&mut self.random_seed, seed);
+        self.stack.push(Value::Float(previous_seed));
+    }
+
+    /// `match(s, r)` pushes where `r` first matches in `s` and sets RSTART
+    /// to it and RLENGTH to the match's length, or to 0 and -1 if none.
+    pub fn exec_match(&mut self) {
+        let arguments = self.pop_arguments(2, "MATCH");
+        let source = self.convert_to_string(&arguments[0]);
+        let (start, length) = match self.regex_operand(&arguments[1]).find(&source) {
+            Some(found) => (
+                string_length(&source[..found.start()]) as i64 + 1,
+                string_length(found.as_str()) as i64,
+            ),
+            None => (0, -1),
+        };
+        self.environ.insert("RSTART".to_string(), Some(Value::Number(start)));
+        self.environ.insert("RLENGTH".to_string(), Some(Value::Number(length)));
+        self.stack.push(Value::Number(start));
+    }
+
+    /// `sub` and `gsub` leave the target's new value under the number of
+    /// replacements, for the compiler to store back when it isn't zero.
+    pub fn exec_substitute(&mut self, global: bool) {
+        let instruction_name = if global { "GSUB" } else { "SUB" };
+        let arguments = self.pop_arguments(3, instruction_name);
+        let regex = self.regex_operand(&arguments[0]);
+        let replacement = self.convert_to_string(&arguments[1]);
+        let target = self.convert_to_string(&arguments[2]);
+        let (result, count) = substitute(&target, &regex, &replacement, global);
+        self.stack.push(Value::StringLiteral(result));
+        self.stack.push(Value::Number(count as i64));
+    }
+
+    // A dynamic regex is the string form of any value.
+    fn regex_operand(&self, operand: &Value) -> Regex {
+        let pattern = match operand {
+            Value::RegexPattern(pattern) => pattern.clone(),
+            other => self.convert_to_string(other),
+        };
+        match get_or_compile(&pattern) {
+            Ok(regex) => regex,
+            Err(e) => {
+                exit_err!("Invalid regular expression `{}`: {}", pattern, e);
+            }
         }
+    }
+
+    pub fn execute_toupper(&mut self) {
+        self.exec_unary_builtin(Value::to_upper, "TOUPPER");
+    }
 
-        if let (Some(Value::AssociativeIdentifier(ref array_id, ref idx)), Some(value_to_store)) =
-            (self.stack.pop().unwrap(), self.stack.pop().unwrap())
-        {
-            let mut key = array_id.clone();
-            key.push_str(idx);
+    pub fn execute_tolower(&mut self) {
+        self.exec_unary_builtin(Value::to_lower, "TOLOWER");
+    }
+
+    pub fn execute_int(&mut self) {
+        self.exec_unary_builtin(Value::int, "INT");
+    }
+
+    pub fn execute_system(&mut self) {
+        let command_line = match self.stack.pop() {
+            Some(command_line) => command_line.to_string(),
+            None => {
+                exit_err!("Not enough operands on the stack for SYSTEM");
+            }
+        };
 
-            self.environ.insert(key.clone(), Some(value_to_store));
+        self.flush_all();
+        let result = Value::shell_command(&command_line).run_command();
+        self.push_result(result, "SYSTEM");
+    }
+
+    // Output written so far has to appear before anything a child command
+    // prints.
+    fn flush_all(&mut self) {
+        match self.io.flush_all() {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => self.exit_on_broken_pipe(),
+            Err(e) => {
+                exit_err!("Error flushing output: {}", e);
+            }
+        }
+    }
+
+    // Standard output is gone, but files and pipes still get what was
+    // printed to them.
+    fn exit_on_broken_pipe(&mut self) -> ! {
+        self.io.close_outputs();
+        std::process::exit(0)
+    }
+
+    pub fn exec_close_stream(&mut self) {
+        let stream_name = match self.stack.pop() {
+            Some(stream_name) => stream_name.to_string(),
+            None => {
+                exit_err!("Not enough operands on the stack for CLOSE_STREAM");
+            }
+        };
+
+        let status = self.io.close(&stream_name);
+        self.stack.push(Value::Number(status as i64));
+    }
+
+    /// `fflush(name)` flushes one output; `fflush()` (an empty name) flushes
+    /// them all.
+    pub fn exec_fflush(&mut self) {
+        let stream_name = match self.stack.pop() {
+            Some(stream_name) => stream_name.to_string(),
+            None => {
+                exit_err!("Not enough operands on the stack for FFLUSH");
+            }
+        };
+
+        let status = if stream_name.is_empty() {
+            self.flush_all();
+            0
         } else {
-            exit_err!("Invalid operand types for STORE_ASSOCIATIVE_ARRAY_VALUE");
+            self.io.flush(&stream_name)
+        };
+        self.stack.push(Value::Number(status as i64));
+    }
+
+    /// `split(s, arr [, sep])`: splits `s` into `arr` on `sep`, or on FS
+    /// when it is omitted, and pushes the element count.
+    pub fn exec_split(&mut self, array_id: &str, argument_count: usize) {
+        let arguments = self.pop_arguments(argument_count, "SPLIT");
+        let separator = match arguments.get(1) {
+            Some(separator) => separator.clone(),
+            None => Value::StringLiteral(self.special_variable("FS")),
+        };
+
+        let mut elements = Value::Uninitialized;
+        let element_count = arguments[0].split(&separator, &mut elements);
+        if let Value::ArrayLiteral(elements) = elements {
+            let array = self.array_mut(array_id);
+            array.clear();
+            array.extend(elements);
+        }
+        self.push_result(element_count, "SPLIT");
+    }
+
+    /// `parsekv(s, arr [, pairsep [, kvsep]])`: fills `arr` from the
+    /// `key=value` pairs in `s` and pushes how many there were.
+    pub fn exec_parse_kv(&mut self, array_id: &str, argument_count: usize) {
+        let arguments = self.pop_arguments(argument_count, "PARSEKV");
+        let separator = |index: usize, default: &str| {
+            arguments
+                .get(index)
+                .map_or(default.to_string(), |separator| separator.to_string())
+        };
+        let (pair_separator, key_value_separator) = (separator(1, ";"), separator(2, "="));
+
+        let pairs = match arguments[0].parse_key_values(&pair_separator, &key_value_separator) {
+            Some(Value::ArrayLiteral(pairs)) => pairs,
+            _ => {
+                exit_err!("Invalid operand types for PARSEKV");
+            }
+        };
+
+        let pair_count = pairs.len();
+        let array = self.array_mut(array_id);
+        array.clear();
+        array.extend(pairs);
+        self.stack.push(Value::Number(pair_count as i64));
+    }
+
+    pub fn exec_array_in(&mut self, array_id: &str) {
+        let key = self.pop_subscript("ARRAY_IN");
+        let is_member = if array_id == SYMTAB {
+            self.environ.contains_key(&key)
+        } else {
+            self.array(array_id).is_some_and(|array| array.contains_key(&key))
+        };
+        self.stack.push(Value::Bool(is_member));
+    }
+
+    pub fn exec_join_subscripts(&mut self, subscript_count: usize) {
+        if self.stack.len() < subscript_count {
+            exit_err!("Not enough operands on the stack for JOIN_SUBSCRIPTS");
+        }
+
+        let subscript_separator = match self.environ.get("SUBSEP") {
+            Some(Some(separator)) => separator.to_string(),
+            _ => DEFAULT_SUBSEP.to_string(),
+        };
+
+        let subscripts: Vec<String> = self
+            .stack
+            .split_off(self.stack.len() - subscript_count)
+            .iter()
+            .map(|subscript| self.subscript_key(subscript))
+            .collect();
+
+        self.stack
+            .push(Value::StringLiteral(subscripts.join(&subscript_separator)));
+    }
+
+    /// Assigns the next key of the array on the stack to `variable_name`,
+    /// or pops it and jumps to `exit_target` once every key has been seen.
+    /// The first step replaces the array with a copy, so the loop body may
+    /// add and delete elements.
+    pub fn exec_for_in_next(&mut self, variable_name: String, exit_target: usize) {
+        let keys = match self.stack.last() {
+            Some(Value::Array(handle)) => Some(self.arrays[*handle].clone()),
+            Some(Value::Uninitialized) => Some(HashMap::new()),
+            Some(Value::ArrayLiteral(_)) => None,
+            Some(_) => {
+                exit_err!("a scalar cannot be iterated over with `for (... in ...)`");
+            }
+            None => {
+                exit_err!("Not enough operands on the stack for FOR_IN_NEXT");
+            }
+        };
+        if let Some(keys) = keys {
+            *self.stack.last_mut().unwrap() = Value::ArrayLiteral(keys);
+        }
+        let next_key = match self.stack.last_mut() {
+            Some(Value::ArrayLiteral(remaining_keys)) => {
+                let key = remaining_keys.keys().next().cloned();
+                if let Some(ref key) = key {
+                    remaining_keys.remove(key);
+                }
+                key
+            }
+            _ => {
+                exit_err!("Invalid operand types for FOR_IN_NEXT");
+            }
+        };
+
+        match next_key {
+            Some(key) => self.assign_variable(variable_name, Value::StringLiteral(key)),
+            None => {
+                self.stack.pop();
+                self.pc = exit_target;
+            }
         }
     }
 
@@ -431,3 +1684,17 @@ impl StackVM {
         }
     }
 }
+
+fn numeric_operand(value: Value) -> Value {
+    match value {
+        Value::Number(_) | Value::BigNumber(_) | Value::Float(_) => value,
+        other => {
+            let number = other.to_number();
+            if number.fract() == 0.0 && number.abs() < i64::MAX as f64 {
+                Value::Number(number as i64)
+            } else {
+                Value::Float(number)
+            }
+        }
+    }
+}
@@ -1,23 +1,180 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+
 mod value;
 mod machine;
 mod awkio;
 mod parser;
+mod format;
+mod compiler;
+
+use machine::{StackVM, DEFAULT_MAX_CALL_DEPTH};
+use value::Value;
+
+/// The name every diagnostic starts with.
+pub const PROG_NAME: &str = "brawk";
 
+/// Reports a fatal error as `brawk: <reason>` and exits with status 1.
 #[macro_export]
 macro_rules! exit_err {
     ($reason:expr) => {
-            eprintln!("{}", $reason);
-            eprintln!("This caused RustyAWK to exit with status 1"); 
+            eprintln!("{}: {}", $crate::PROG_NAME, $reason);
             std::process::exit(1)
     };
 
     ($fmt:literal, $($arg:expr),+ $(,)?) => {
-            eprintln!($fmt, $($arg),+);
-            eprintln!("This caused RustyAWK to exit with status 1");
+            eprintln!("{}: {}", $crate::PROG_NAME, format!($fmt, $($arg),+));
             std::process::exit(1)
     };
 }
 
+fn read_program_file(path: &str) -> io::Result<String> {
+    if path == "-" {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source)?;
+        Ok(source)
+    } else {
+        fs::read_to_string(path)
+    }
+}
+
+fn string_array<K: ToString, V: Into<String>>(entries: impl IntoIterator<Item = (K, V)>) -> Value {
+    let array: HashMap<String, Box<Value>> = entries
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), Box::new(Value::from_input(value))))
+        .collect();
+    Value::ArrayLiteral(array)
+}
+
+fn variable_assignment(assignment: &str) -> (String, String) {
+    match parser::parse_assignment(assignment) {
+        Some(assignment) => assignment,
+        // Only scalars can be set before the program runs.
+        None if assignment.split('=').next().is_some_and(|name| name.contains('[')) => {
+            exit_err!(
+                "Invalid -v assignment `{}`; array elements cannot be assigned with -v",
+                assignment
+            );
+        }
+        None => {
+            exit_err!("Invalid -v assignment `{}`; expected var=value", assignment);
+        }
+    }
+}
+
+fn field_separator(separator: &str) -> String {
+    // POSIX: `-F t` means a tab.
+    if separator == "t" {
+        "\t".to_string()
+    } else {
+        parser::unescape(separator)
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut program_source = String::new();
+    let mut program_from_stdin = false;
+    let mut program_from_file = false;
+    let mut input_files = vec![];
+    let mut optimize = true;
+    let mut assignments = vec![];
+    let mut max_call_depth = DEFAULT_MAX_CALL_DEPTH;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bignum" => value::set_bignum_mode(true),
+            "-b" | "--characters-as-bytes" => value::set_characters_as_bytes(true),
+            "--no-optimize" => optimize = false,
+            "--max-call-depth" => {
+                max_call_depth = match args.next().map(|depth| depth.parse()) {
+                    Some(Ok(depth)) => depth,
+                    _ => {
+                        exit_err!("Option --max-call-depth requires a number of calls");
+                    }
+                };
+            }
+            "-f" => {
+                let path = match args.next() {
+                    Some(path) => path,
+                    None => {
+                        exit_err!("Option -f requires a program file argument");
+                    }
+                };
+
+                program_from_file = true;
+                program_from_stdin |= path == "-";
+                match read_program_file(&path) {
+                    Ok(source) => program_source.push_str(&source),
+                    Err(e) => {
+                        exit_err!("Cannot read program file `{}`: {}", path, e);
+                    }
+                }
+            }
+            "-v" => match args.next() {
+                Some(assignment) => assignments.push(variable_assignment(&assignment)),
+                None => {
+                    exit_err!("Option -v requires a var=value argument");
+                }
+            },
+            "-F" => match args.next() {
+                Some(separator) => {
+                    assignments.push(("FS".to_string(), field_separator(&separator)))
+                }
+                None => {
+                    exit_err!("Option -F requires a field separator argument");
+                }
+            },
+            option if option.len() > 2 && option.starts_with("-v") => {
+                assignments.push(variable_assignment(&option[2..]))
+            }
+            option if option.len() > 2 && option.starts_with("-F") => {
+                assignments.push(("FS".to_string(), field_separator(&option[2..])))
+            }
+            _ => input_files.push(arg),
+        }
+    }
+
+    if !program_from_file {
+        if input_files.is_empty() {
+            exit_err!(
+                "No program text given; usage: brawk [-F fs] [-v var=value] \
+                 [-f progfile | 'program'] [file ...]"
+            );
+        }
+        program_source = input_files.remove(0);
+    }
+
+    if program_from_stdin && input_files.iter().any(|file| file == "-") {
+        exit_err!("Standard input cannot supply both the program (-f -) and input data (-)");
+    }
+
+    let program = match parser::parse(&program_source) {
+        Ok(program) => program,
+        Err(error) => {
+            eprintln!("{}: {}", PROG_NAME, error);
+            std::process::exit(2);
+        }
+    };
+    let compiled = compiler::compile(&program, optimize);
+    let mut vm = StackVM::new(compiled.instructions);
+    vm.set_max_call_depth(max_call_depth);
+    vm.set_end_start(compiled.end_start);
+    for function in compiled.functions {
+        vm.define_function(&function.name, function.entry, function.parameters);
+    }
+    for (name, value) in assignments {
+        vm.set_variable(&name, Value::from_input(value));
+    }
+
+    let argv = std::iter::once("awk".to_string()).chain(input_files);
+    vm.set_variable("ARGC", Value::Number(argv.clone().count() as i64));
+    vm.set_variable("ARGV", string_array(argv.enumerate()));
+    let environment = std::env::vars_os().map(|(name, value)| {
+        (name.to_string_lossy().into_owned(), value.to_string_lossy().into_owned())
+    });
+    vm.set_variable("ENVIRON", string_array(environment));
+    vm.run();
 }
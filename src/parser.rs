@@ -1,14 +1,14 @@
+use std::fmt;
+
 #[derive(Debug)]
-enum AstNode {
+pub enum AstNode {
     Program(Vec<AstNode>),
     PatternActionRule(Option<Box<AstNode>>, Box<AstNode>),
-    Pattern(Box<AstNode>),
     PatternExpression(Box<AstNode>),
+    RangePattern(Box<AstNode>, Box<AstNode>),
     Action(Box<AstNode>),
     FunctionDefinition(String, Vec<String>, Box<AstNode>),
-    ParameterList(Vec<String>),
     StatementList(Vec<AstNode>),
-    Statement(Box<AstNode>),
     IfStatement(Box<AstNode>, Box<AstNode>, Option<Box<AstNode>>),
     WhileStatement(Box<AstNode>, Box<AstNode>),
     ForStatement(
@@ -17,82 +17,190 @@ enum AstNode {
         Option<Box<AstNode>>,
         Box<AstNode>,
     ),
+    ForInStatement(String, String, Box<AstNode>),
     DoWhileStatement(Box<AstNode>, Box<AstNode>),
-    ForInitializer(Box<AstNode>),
-    ForIterator(Box<AstNode>),
     PrintStatement(Option<Box<AstNode>>, Option<Box<AstNode>>),
     PrintfStatement(Box<AstNode>, Box<AstNode>, Option<Box<AstNode>>),
     NextStatement,
+    NextFileStatement,
+    BreakStatement,
+    ContinueStatement,
     ExitStatement(Option<Box<AstNode>>),
     ReturnStatement(Option<Box<AstNode>>),
     DeleteStatement(Box<AstNode>),
-    VariableAssignment(String, Box<AstNode>),
     ArrayElement(String, Box<AstNode>),
+    FieldReference(Box<AstNode>),
+    InExpression(Box<AstNode>, String),
     ExpressionList(Vec<AstNode>),
-    Expression(Box<AstNode>),
+    AssignmentExpression(Box<AstNode>, Box<AstNode>),
+    CompoundAssignmentExpression(Box<AstNode>, String, Box<AstNode>),
+    TernaryExpression(Box<AstNode>, Box<AstNode>, Box<AstNode>),
     LogicalOrExpression(Box<AstNode>, Vec<AstNode>),
     LogicalAndExpression(Box<AstNode>, Vec<AstNode>),
-    InclusiveOrExpression(Box<AstNode>, Vec<AstNode>),
-    ExclusiveOrExpression(Box<AstNode>, Vec<AstNode>),
     AndExpression(Box<AstNode>, Vec<AstNode>),
+    MatchExpression(Box<AstNode>, String, Box<AstNode>),
     EqualityExpression(Box<AstNode>, String, Box<AstNode>),
     RelationalExpression(Box<AstNode>, String, Box<AstNode>),
-    ShiftExpression(Box<AstNode>, String, Box<AstNode>),
+    ConcatenationExpression(Box<AstNode>, Box<AstNode>),
     AdditiveExpression(Box<AstNode>, String, Box<AstNode>),
     MultiplicativeExpression(Box<AstNode>, String, Box<AstNode>),
-    PrimaryExpression(Box<AstNode>),
+    UnaryExpression(String, Box<AstNode>),
+    ExponentExpression(Box<AstNode>, Box<AstNode>),
+    PostfixExpression(Box<AstNode>, String),
     Variable(String),
     Constant(Constant),
     FunctionCall(String, Box<Option<AstNode>>),
+    IndirectFunctionCall(String, Box<Option<AstNode>>),
     ArgumentList(Vec<AstNode>),
-    Redirection(String),
-    IntegerLiteral(String),
-    FloatingPointLiteral(String),
-    StringLiteral(String),
+    Redirection(String, Box<AstNode>),
+    GetlineExpression(Option<Box<AstNode>>, Option<Box<AstNode>>),
+    RegexLiteral(String),
     Nil
 }
 
 #[derive(Debug)]
-enum Constant {
+#[allow(clippy::enum_variant_names)]
+pub enum Constant {
     IntegerLiteral(String),
     FloatingPointLiteral(String),
     StringLiteral(String),
 }
 
+/// A stretch of the program text: byte offsets `start..end`, and the
+/// 1-based line and column (in characters) where it starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A syntax error: what the parser expected at `span` and the character it
+/// found there instead.
+#[derive(Debug)]
+pub struct ParseError {
+    pub span: Span,
+    pub expected: String,
+    pub found: Option<char>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "syntax error at line {}, column {}: expected {}, found ",
+            self.span.line, self.span.column, self.expected
+        )?;
+        match self.found {
+            Some('\n') => write!(f, "newline"),
+            Some(ch) => write!(f, "`{}`", ch),
+            None => write!(f, "end of program"),
+        }
+    }
+}
+
 struct Lexer<'a> {
     input: &'a str,
     position: usize,
+    line: usize,
+    column: usize,
+    // Set while parsing an unparenthesized `print` list, where `>` and `|`
+    // start a redirection instead of acting as operators.
+    in_print: bool,
 }
 
 impl<'a> Lexer<'a> {
     fn new(input: &'a str) -> Lexer<'a> {
-        Lexer { input, position: 0 }
+        Lexer { input, position: 0, line: 1, column: 1, in_print: false }
     }
 
     fn peek(&self) -> Option<char> {
-        self.input.chars().nth(self.position)
+        self.input[self.position..].chars().next()
+    }
+
+    fn peek_ahead(&self, offset: usize) -> Option<char> {
+        self.input[self.position..].chars().nth(offset)
     }
 
     fn advance(&mut self) {
-        if let Some(ch) = self.input.chars().nth(self.position) {
+        if let Some(ch) = self.peek() {
             self.position += ch.len_utf8();
+            if ch == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
         }
     }
 
-    fn previous_lexeme(&self) -> &'a str {
-        &self.input[..self.position]
+    fn starts_with(&self, text: &str) -> bool {
+        self.input[self.position..].starts_with(text)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        self.starts_with(keyword)
+            && !self.input[self.position + keyword.len()..]
+                .starts_with(|ch: char| ch.is_alphanumeric() || ch == '_')
+    }
+
+    fn consume_keyword(&mut self, keyword: &str) -> bool {
+        if !self.peek_keyword(keyword) {
+            return false;
+        }
+        for _ in keyword.chars() {
+            self.advance();
+        }
+        true
+    }
+
+    fn consume_operator(&mut self, operators: &[&str]) -> Option<String> {
+        let operator = operators.iter().find(|operator| self.starts_with(operator))?;
+        for _ in operator.chars() {
+            self.advance();
+        }
+        Some(operator.to_string())
+    }
+
+    // A statement ending in a nested block's `}` needs no other terminator,
+    // as in `if (x) { y() } z()`.
+    fn follows_block(&self) -> bool {
+        self.input[..self.position].trim_end().ends_with('}')
     }
 
     fn skip_whitespace(&mut self) {
         while let Some(ch) = self.peek() {
             if ch.is_whitespace() {
                 self.advance();
+            } else if ch == '#' {
+                self.skip_comment();
             } else {
                 break;
             }
         }
     }
 
+    fn skip_blanks(&mut self) {
+        while let Some(ch) = self.peek() {
+            if ch == ' ' || ch == '\t' {
+                self.advance();
+            } else if ch == '#' {
+                self.skip_comment();
+            } else {
+                break;
+            }
+        }
+    }
+
+    // A comment runs to the end of the line; the newline still terminates
+    // the statement before it.
+    fn skip_comment(&mut self) {
+        while self.peek().is_some_and(|ch| ch != '\n') {
+            self.advance();
+        }
+    }
+
     fn consume_identifier(&mut self) -> String {
         let start = self.position;
         while let Some(ch) = self.peek() {
@@ -107,11 +215,18 @@ impl<'a> Lexer<'a> {
 
     fn consume_string_literal(&mut self) -> String {
         let mut value = String::new();
-        self.advance(); 
+        self.advance();
         while let Some(ch) = self.peek() {
             if ch == '"' {
                 self.advance();
                 break;
+            } else if ch == '\\' {
+                self.advance();
+                match self.peek() {
+                    Some(escaped) => value.push(escape_sequence(escaped)),
+                    None => break,
+                }
+                self.advance();
             } else {
                 value.push(ch);
                 self.advance();
@@ -120,6 +235,25 @@ impl<'a> Lexer<'a> {
         value
     }
 
+    fn consume_regex_literal(&mut self) -> String {
+        let mut pattern = String::new();
+        self.advance();
+        while let Some(ch) = self.peek() {
+            if ch == '/' {
+                self.advance();
+                break;
+            } else if ch == '\\' && self.peek_ahead(1) == Some('/') {
+                pattern.push('/');
+                self.advance();
+                self.advance();
+            } else {
+                pattern.push(ch);
+                self.advance();
+            }
+        }
+        pattern
+    }
+
     fn consume_digit_sequence(&mut self) -> String {
         let start = self.position;
         while let Some(ch) = self.peek() {
@@ -140,553 +274,699 @@ impl<'a> Lexer<'a> {
             self.advance();
             value.push_str(&self.consume_digit_sequence());
         }
-        if let Some(ch) = self.peek() {
-            if ch == 'e' || ch == 'E' {
-                value.push(ch);
+        // An exponent needs digits, so `1e` is the number 1 followed by `e`.
+        let exponent_digit = match self.peek_ahead(1) {
+            Some('+') | Some('-') => self.peek_ahead(2),
+            next => next,
+        };
+        if let (Some(marker @ ('e' | 'E')), Some('0'..='9')) = (self.peek(), exponent_digit) {
+            value.push(marker);
+            self.advance();
+            if let Some(sign @ ('+' | '-')) = self.peek() {
+                value.push(sign);
                 self.advance();
-                if let Some(sign) = self.peek() {
-                    if sign == '+' || sign == '-' {
-                        value.push(sign);
-                        self.advance();
-                    }
-                }
-                value.push_str(&self.consume_digit_sequence());
             }
+            value.push_str(&self.consume_digit_sequence());
         }
         value
     }
 
-    fn consume_character(&mut self) -> char {
-        self.peek().unwrap_or('\0')
+    /// An empty span at the current position, which `rewind` returns to.
+    fn location(&self) -> Span {
+        Span { start: self.position, end: self.position, line: self.line, column: self.column }
     }
 
-    fn consume_redirection(&mut self) -> String {
-        let mut redirection = String::new();
-        while let Some(ch) = self.peek() {
-            if ch.is_alphabetic() {
-                redirection.push(ch);
-                self.advance();
-            } else {
-                break;
-            }
+    fn rewind(&mut self, location: Span) {
+        self.position = location.start;
+        self.line = location.line;
+        self.column = location.column;
+    }
+
+    fn error(&self, expected: &str) -> ParseError {
+        let found = self.peek();
+        let mut span = self.location();
+        span.end += found.map_or(0, char::len_utf8);
+        ParseError { span, expected: expected.to_string(), found }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("`{}`", expected)));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        if !self.consume_keyword(keyword) {
+            return Err(self.error(&format!("`{}`", keyword)));
         }
-        redirection
+        Ok(())
     }
+
+    fn at_redirection(&self) -> bool {
+        self.in_print && matches!(self.peek(), Some('>') | Some('|'))
+    }
+}
+
+pub fn parse(source: &str) -> Result<AstNode, ParseError> {
+    let mut lexer = Lexer::new(source);
+    parse_program(&mut lexer)
 }
 
-fn parse_program(lexer: &mut Lexer) -> AstNode {
+fn parse_program(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
     let mut program = vec![];
+    lexer.skip_whitespace();
     while lexer.peek().is_some() {
-        program.push(parse_pattern_action_rule(lexer));
+        if lexer.peek_keyword("function") {
+            program.push(parse_function_definition(lexer)?);
+        } else {
+            program.push(parse_pattern_action_rule(lexer)?);
+        }
+        // Rules may share a line, optionally separated by `;`.
+        lexer.skip_whitespace();
+        while lexer.peek() == Some(';') {
+            lexer.advance();
+            lexer.skip_whitespace();
+        }
     }
-    AstNode::Program(program)
+    Ok(AstNode::Program(program))
 }
 
-fn parse_pattern_action_rule(lexer: &mut Lexer) -> AstNode {
-    let pattern = if lexer.peek() == Some('[') {
-        lexer.advance();
-        let pattern_expression = parse_pattern_expression(lexer);
-        Some(Box::new(AstNode::PatternExpression(Box::new(pattern_expression))))
-    } else if lexer.peek() == Some('B') {
-        lexer.advance();
+fn parse_pattern_action_rule(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let pattern = if lexer.consume_keyword("BEGIN") {
         Some(Box::new(AstNode::PatternExpression(Box::new(
             AstNode::Variable("BEGIN".to_string()),
         ))))
-    } else if lexer.peek() == Some('E') {
-        lexer.advance();
+    } else if lexer.consume_keyword("END") {
         Some(Box::new(AstNode::PatternExpression(Box::new(
             AstNode::Variable("END".to_string()),
         ))))
+    } else if lexer.peek() != Some('{') {
+        let pattern_expression = parse_pattern_expression(lexer)?;
+        lexer.skip_blanks();
+        let pattern = if lexer.peek() == Some(',') {
+            lexer.advance();
+            lexer.skip_whitespace();
+            let end_expression = parse_pattern_expression(lexer)?;
+            AstNode::RangePattern(Box::new(pattern_expression), Box::new(end_expression))
+        } else {
+            AstNode::PatternExpression(Box::new(pattern_expression))
+        };
+
+        // A pattern without an action on the same line prints the record.
+        lexer.skip_blanks();
+        if matches!(lexer.peek(), None | Some(';') | Some('\n')) {
+            let print = AstNode::PrintStatement(None, None);
+            let action = AstNode::Action(Box::new(AstNode::StatementList(vec![print])));
+            return Ok(AstNode::PatternActionRule(Some(Box::new(pattern)), Box::new(action)));
+        }
+        Some(Box::new(pattern))
     } else {
         None
     };
 
-    let action = parse_action(lexer);
-    AstNode::PatternActionRule(pattern, Box::new(action))
+    let action = parse_action(lexer)?;
+    Ok(AstNode::PatternActionRule(pattern, Box::new(action)))
+}
+
+fn parse_function_definition(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("function")?;
+    lexer.skip_blanks();
+    let name = parse_name(lexer, "a function name")?;
+    lexer.skip_blanks();
+    lexer.expect('(')?;
+    lexer.skip_whitespace();
+    let parameters = if lexer.peek() != Some(')') {
+        parse_parameter_list(lexer)?
+    } else {
+        vec![]
+    };
+    lexer.expect(')')?;
+    let body = parse_action(lexer)?;
+    Ok(AstNode::FunctionDefinition(name, parameters, Box::new(body)))
+}
+
+fn parse_parameter_list(lexer: &mut Lexer) -> Result<Vec<String>, ParseError> {
+    let mut parameters = vec![parse_name(lexer, "a parameter name")?];
+    lexer.skip_whitespace();
+    while lexer.peek() == Some(',') {
+        lexer.advance();
+        lexer.skip_whitespace();
+        parameters.push(parse_name(lexer, "a parameter name")?);
+        lexer.skip_whitespace();
+    }
+    Ok(parameters)
 }
 
-fn parse_pattern_expression(lexer: &mut Lexer) -> AstNode {
+fn parse_pattern_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
     parse_expression(lexer)
 }
 
-fn parse_action(lexer: &mut Lexer) -> AstNode {
+fn parse_action(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
     lexer.skip_whitespace();
-    assert_eq!(lexer.peek(), Some('{'));
-    lexer.advance();
-    let statement_list = parse_statement_list(lexer);
-    assert_eq!(lexer.peek(), Some('}'));
-    lexer.advance();
-    AstNode::Action(Box::new(statement_list))
+    lexer.expect('{')?;
+    let statement_list = parse_statement_list(lexer)?;
+    lexer.expect('}')?;
+    Ok(AstNode::Action(Box::new(statement_list)))
 }
 
-fn parse_statement_list(lexer: &mut Lexer) -> AstNode {
-    let mut statements = vec![parse_statement(lexer)];
-    while lexer.peek() == Some(';') {
-        lexer.advance();
-        statements.push(parse_statement(lexer));
+fn parse_statement_list(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.skip_whitespace();
+    // An empty block (`pattern {}`) selects records without doing anything.
+    if lexer.peek() == Some('}') {
+        return Ok(AstNode::StatementList(Vec::new()));
+    }
+    let mut statements = vec![parse_statement(lexer)?];
+    loop {
+        lexer.skip_blanks();
+        if matches!(lexer.peek(), Some(';') | Some('\n')) {
+            lexer.advance();
+        } else if !lexer.follows_block() {
+            break;
+        }
+        lexer.skip_whitespace();
+        if matches!(lexer.peek(), Some('}') | None) {
+            break;
+        }
+        statements.push(parse_statement(lexer)?);
     }
-    AstNode::StatementList(statements)
+    lexer.skip_whitespace();
+    Ok(AstNode::StatementList(statements))
 }
 
-fn parse_statement(lexer: &mut Lexer) -> AstNode {
-    if lexer.peek() == Some('i') {
+fn parse_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    // An empty statement, as in `x = 1;; y = 2` or `while (busy()) ;`. The
+    // `;` is left for the caller to consume as a terminator.
+    if lexer.peek() == Some(';') {
+        Ok(AstNode::Nil)
+    } else if lexer.peek() == Some('{') {
+        parse_action(lexer)
+    } else if lexer.peek_keyword("if") {
         parse_if_statement(lexer)
-    } else if lexer.peek() == Some('w') {
+    } else if lexer.peek_keyword("while") {
         parse_while_statement(lexer)
-    } else if lexer.peek() == Some('f') {
+    } else if lexer.peek_keyword("for") {
         parse_for_statement(lexer)
-    } else if lexer.peek() == Some('d') {
+    } else if lexer.peek_keyword("delete") {
+        parse_delete_statement(lexer)
+    } else if lexer.peek_keyword("do") {
         parse_do_while_statement(lexer)
-    } else if lexer.peek() == Some('p') {
-        parse_print_statement(lexer)
-    } else if lexer.peek() == Some('p') {
+    } else if lexer.peek_keyword("printf") {
         parse_printf_statement(lexer)
-    } else if lexer.peek() == Some('n') {
+    } else if lexer.peek_keyword("print") {
+        parse_print_statement(lexer)
+    } else if lexer.consume_keyword("break") {
+        Ok(AstNode::BreakStatement)
+    } else if lexer.consume_keyword("continue") {
+        Ok(AstNode::ContinueStatement)
+    } else if lexer.peek_keyword("next") {
         parse_next_statement(lexer)
-    } else if lexer.peek() == Some('e') {
+    } else if lexer.peek_keyword("nextfile") {
+        parse_next_file_statement(lexer)
+    } else if lexer.peek_keyword("exit") {
         parse_exit_statement(lexer)
-    } else if lexer.peek() == Some('r') {
+    } else if lexer.peek_keyword("return") {
         parse_return_statement(lexer)
-    } else if lexer.peek() == Some('d') {
-        parse_delete_statement(lexer)
     } else {
-        parse_variable_assignment(lexer)
+        parse_expression(lexer)
     }
 }
 
-fn parse_if_statement(lexer: &mut Lexer) -> AstNode {
-    assert_eq!(lexer.peek(), Some('i'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('('));
-    lexer.advance();
-    let condition = parse_expression(lexer);
-    assert_eq!(lexer.peek(), Some(')'));
-    lexer.advance();
-    let if_body = parse_statement(lexer);
-    let else_body = if lexer.peek() == Some('e') {
+fn at_statement_end(lexer: &Lexer) -> bool {
+    matches!(lexer.peek(), None | Some(';') | Some('\n') | Some('}'))
+}
+
+fn parse_condition(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.skip_blanks();
+    lexer.expect('(')?;
+    let condition = parse_expression(lexer)?;
+    lexer.skip_blanks();
+    lexer.expect(')')?;
+    Ok(condition)
+}
+
+fn parse_if_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("if")?;
+    let condition = parse_condition(lexer)?;
+    lexer.skip_whitespace();
+    let if_body = parse_statement(lexer)?;
+
+    let start = lexer.location();
+    lexer.skip_blanks();
+    if lexer.peek() == Some(';') {
         lexer.advance();
-        Some(Box::new(parse_statement(lexer)))
+    }
+    lexer.skip_whitespace();
+    let else_body = if lexer.consume_keyword("else") {
+        lexer.skip_whitespace();
+        Some(Box::new(parse_statement(lexer)?))
     } else {
+        lexer.rewind(start);
         None
     };
-    AstNode::IfStatement(Box::new(condition), Box::new(if_body), else_body)
+    Ok(AstNode::IfStatement(Box::new(condition), Box::new(if_body), else_body))
 }
 
-fn parse_while_statement(lexer: &mut Lexer) -> AstNode {
-    assert_eq!(lexer.peek(), Some('w'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('('));
-    lexer.advance();
-    let condition = parse_expression(lexer);
-    assert_eq!(lexer.peek(), Some(')'));
-    lexer.advance();
-    let body = parse_statement(lexer);
-    AstNode::WhileStatement(Box::new(condition), Box::new(body))
+fn parse_while_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("while")?;
+    let condition = parse_condition(lexer)?;
+    lexer.skip_whitespace();
+    let body = parse_statement(lexer)?;
+    Ok(AstNode::WhileStatement(Box::new(condition), Box::new(body)))
 }
 
-fn parse_for_statement(lexer: &mut Lexer) -> AstNode {
-    assert_eq!(lexer.peek(), Some('f'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('('));
-    lexer.advance();
+fn parse_for_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("for")?;
+    lexer.skip_blanks();
+    lexer.expect('(')?;
+    lexer.skip_blanks();
+    if let Some(for_in_statement) = parse_for_in_statement(lexer)? {
+        return Ok(for_in_statement);
+    }
     let initializer = if lexer.peek() != Some(';') {
-        Box::new(parse_for_initializer(lexer))
+        Box::new(parse_for_initializer(lexer)?)
     } else {
         Box::new(AstNode::Nil)
     };
-    assert_eq!(lexer.peek(), Some(';'));
-    lexer.advance();
+    lexer.expect(';')?;
     let condition = if lexer.peek() != Some(';') {
-        Some(Box::new(parse_expression(lexer)))
+        Some(Box::new(parse_expression(lexer)?))
     } else {
         None
     };
-    assert_eq!(lexer.peek(), Some(';'));
-    lexer.advance();
+    lexer.expect(';')?;
     let iterator = if lexer.peek() != Some(')') {
-        Some(Box::new(parse_for_iterator(lexer)))
+        Some(Box::new(parse_for_iterator(lexer)?))
     } else {
         None
     };
-    assert_eq!(lexer.peek(), Some(')'));
-    lexer.advance();
-    let body = parse_statement(lexer);
-    AstNode::ForStatement(initializer, condition, iterator, Box::new(body))
+    lexer.expect(')')?;
+    lexer.skip_whitespace();
+    let body = parse_statement(lexer)?;
+    Ok(AstNode::ForStatement(initializer, condition, iterator, Box::new(body)))
 }
 
-fn parse_do_while_statement(lexer: &mut Lexer) -> AstNode {
-    assert_eq!(lexer.peek(), Some('d'));
-    lexer.advance();
-    let body = parse_statement(lexer);
-    assert_eq!(lexer.peek(), Some('w'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('('));
-    lexer.advance();
-    let condition = parse_expression(lexer);
-    assert_eq!(lexer.peek(), Some(')'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some(';'));
+fn parse_for_in_statement(lexer: &mut Lexer) -> Result<Option<AstNode>, ParseError> {
+    let start = lexer.location();
+    let variable = parse_identifier(lexer);
+    lexer.skip_blanks();
+    if variable.is_empty() || !lexer.consume_keyword("in") {
+        lexer.rewind(start);
+        return Ok(None);
+    }
+    lexer.skip_blanks();
+    let array = parse_identifier(lexer);
+    lexer.skip_blanks();
+    if array.is_empty() || lexer.peek() != Some(')') {
+        lexer.rewind(start);
+        return Ok(None);
+    }
     lexer.advance();
-    AstNode::DoWhileStatement(Box::new(body), Box::new(condition))
+    lexer.skip_whitespace();
+    let body = parse_statement(lexer)?;
+    Ok(Some(AstNode::ForInStatement(variable, array, Box::new(body))))
 }
 
-fn parse_for_initializer(lexer: &mut Lexer) -> AstNode {
-    if lexer.peek().unwrap().is_alphabetic() {
-        parse_variable_assignment(lexer)
-    } else {
-        parse_expression(lexer)
+fn parse_do_while_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("do")?;
+    lexer.skip_whitespace();
+    let body = parse_statement(lexer)?;
+    lexer.skip_blanks();
+    if lexer.peek() == Some(';') {
+        lexer.advance();
     }
+    lexer.skip_whitespace();
+    lexer.expect_keyword("while")?;
+    let condition = parse_condition(lexer)?;
+    Ok(AstNode::DoWhileStatement(Box::new(body), Box::new(condition)))
 }
 
-fn parse_for_iterator(lexer: &mut Lexer) -> AstNode {
+fn parse_for_initializer(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
     parse_expression(lexer)
 }
 
-fn parse_print_statement(lexer: &mut Lexer) -> AstNode {
-    assert_eq!(lexer.peek(), Some('p'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('r'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('i'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('n'));
-    lexer.advance();
+fn parse_for_iterator(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    parse_expression(lexer)
+}
 
-    let expression_list = if lexer.peek() != Some('{') {
+fn parse_print_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("print")?;
+    lexer.skip_blanks();
+
+    lexer.in_print = true;
+    let expression_list = if at_statement_end(lexer) || lexer.at_redirection() {
         None
+    } else if let Some(expressions) = parse_grouped_print_list(lexer) {
+        Some(Box::new(expressions))
     } else {
-        Some(Box::new(parse_expression_list(lexer)))
+        Some(Box::new(parse_expression_list(lexer)?))
     };
+    let redirection = parse_redirection(lexer)?;
+    lexer.in_print = false;
+
+    Ok(AstNode::PrintStatement(expression_list, redirection))
+}
 
-    let redirection = if lexer.peek() == Some('>') {
-        Some(Box::new(AstNode::Redirection(parse_redirection(lexer))))
+// `print ("a", "b") > "f"` parenthesizes the whole list. Anything else after
+// the `)`, as in `print (i, j) in a` or `print (1) + 2`, is left to the
+// expression parser.
+fn parse_grouped_print_list(lexer: &mut Lexer) -> Option<AstNode> {
+    if lexer.peek() != Some('(') {
+        return None;
+    }
+    let start = lexer.location();
+    lexer.advance();
+    lexer.skip_whitespace();
+    let expressions = parse_nested(lexer, |lexer| {
+        let expressions = parse_expression_list(lexer).ok()?;
+        lexer.skip_whitespace();
+        lexer.expect(')').ok()?;
+        Some(expressions)
+    });
+    lexer.skip_blanks();
+    if expressions.is_some() && (at_statement_end(lexer) || lexer.at_redirection()) {
+        expressions
     } else {
+        lexer.rewind(start);
         None
-    };
-
-    AstNode::PrintStatement(expression_list, redirection)
+    }
 }
 
-fn parse_printf_statement(lexer: &mut Lexer) -> AstNode {
-    assert_eq!(lexer.peek(), Some('p'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('r'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('i'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('n'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('t'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('f'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('('));
-    lexer.advance();
-    let format_string = parse_format_string(lexer);
-    assert_eq!(lexer.peek(), Some(','));
-    lexer.advance();
-    let expression_list = parse_expression_list(lexer);
-    assert_eq!(lexer.peek(), Some(')'));
-    lexer.advance();
-    let redirection = if lexer.peek() == Some('>') {
+fn parse_printf_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("printf")?;
+    lexer.skip_blanks();
+
+    let parenthesized = lexer.peek() == Some('(');
+    if parenthesized {
         lexer.advance();
-        Some(Box::new(AstNode::Redirection(parse_redirection(lexer))))
     } else {
-        None
-    };
-    AstNode::PrintfStatement(Box::new(format_string), Box::new(expression_list), redirection)
+        lexer.in_print = true;
+    }
+    let format_string = parse_expression(lexer)?;
+    let mut arguments = vec![];
+    lexer.skip_blanks();
+    while lexer.peek() == Some(',') {
+        lexer.advance();
+        lexer.skip_whitespace();
+        arguments.push(parse_expression(lexer)?);
+        lexer.skip_blanks();
+    }
+    if parenthesized {
+        lexer.expect(')')?;
+        lexer.skip_blanks();
+    }
+
+    lexer.in_print = true;
+    let redirection = parse_redirection(lexer)?;
+    lexer.in_print = false;
+    Ok(AstNode::PrintfStatement(
+        Box::new(format_string),
+        Box::new(AstNode::ExpressionList(arguments)),
+        redirection,
+    ))
 }
 
-fn parse_next_statement(lexer: &mut Lexer) -> AstNode {
-    assert_eq!(lexer.peek(), Some('n'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('e'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('x'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('t'));
-    lexer.advance();
-    AstNode::NextStatement
+fn parse_next_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("next")?;
+    Ok(AstNode::NextStatement)
 }
 
-fn parse_exit_statement(lexer: &mut Lexer) -> AstNode {
-    assert_eq!(lexer.peek(), Some('e'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('x'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('i'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('t'));
-    lexer.advance();
-    let expression = if lexer.peek() != Some('{') {
-        Some(Box::new(parse_expression(lexer)))
-    } else {
-        None
-    };
-    AstNode::ExitStatement(expression)
+fn parse_next_file_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("nextfile")?;
+    Ok(AstNode::NextFileStatement)
 }
 
-fn parse_return_statement(lexer: &mut Lexer) -> AstNode {
-    assert_eq!(lexer.peek(), Some('r'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('e'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('t'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('u'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('r'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('n'));
-    lexer.advance();
-    let expression = if lexer.peek() != Some('{') {
-        Some(Box::new(parse_expression(lexer)))
-    } else {
+fn parse_exit_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("exit")?;
+    lexer.skip_blanks();
+    let expression = if at_statement_end(lexer) {
         None
+    } else {
+        Some(Box::new(parse_expression(lexer)?))
     };
-    AstNode::ReturnStatement(expression)
+    Ok(AstNode::ExitStatement(expression))
 }
 
-fn parse_delete_statement(lexer: &mut Lexer) -> AstNode {
-    assert_eq!(lexer.peek(), Some('d'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('e'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('l'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('e'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('t'));
-    lexer.advance();
-    assert_eq!(lexer.peek(), Some('e'));
-    lexer.advance();
-    let array_element = parse_array_element(lexer);
-    AstNode::DeleteStatement(Box::new(array_element))
+fn parse_return_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("return")?;
+    lexer.skip_blanks();
+    let expression = if at_statement_end(lexer) {
+        None
+    } else {
+        Some(Box::new(parse_expression(lexer)?))
+    };
+    Ok(AstNode::ReturnStatement(expression))
 }
 
-fn parse_variable_assignment(lexer: &mut Lexer) -> AstNode {
-    let identifier = parse_identifier(lexer);
-    assert_eq!(lexer.peek(), Some('='));
-    lexer.advance();
-    let expression = parse_expression(lexer);
-    AstNode::VariableAssignment(identifier, Box::new(expression))
+fn parse_delete_statement(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect_keyword("delete")?;
+    lexer.skip_blanks();
+    let target = parse_variable(lexer)?;
+    Ok(AstNode::DeleteStatement(Box::new(target)))
 }
 
-fn parse_array_element(lexer: &mut Lexer) -> AstNode {
-    let identifier = parse_identifier(lexer);
-    assert_eq!(lexer.peek(), Some('['));
-    lexer.advance();
-    let expression_list = parse_expression_list(lexer);
-    assert_eq!(lexer.peek(), Some(']'));
-    lexer.advance();
-    AstNode::ArrayElement(identifier, Box::new(expression_list))
+fn parse_subscripts(lexer: &mut Lexer, identifier: String) -> Result<AstNode, ParseError> {
+    lexer.expect('[')?;
+    let expression_list = parse_nested(lexer, parse_expression_list)?;
+    lexer.expect(']')?;
+    Ok(AstNode::ArrayElement(identifier, Box::new(expression_list)))
 }
 
-fn parse_expression_list(lexer: &mut Lexer) -> AstNode {
-    let mut expressions = vec![parse_expression(lexer)];
+// A newline may follow a `,`, `&&` or `||`, continuing the expression on
+// the next line.
+fn parse_expression_list(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut expressions = vec![parse_expression(lexer)?];
     while lexer.peek() == Some(',') {
         lexer.advance();
-        expressions.push(parse_expression(lexer));
+        lexer.skip_whitespace();
+        expressions.push(parse_expression(lexer)?);
     }
-    AstNode::ExpressionList(expressions)
+    Ok(AstNode::ExpressionList(expressions))
 }
 
-fn parse_expression(lexer: &mut Lexer) -> AstNode {
-    parse_logical_or_expression(lexer)
+fn parse_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    parse_assignment_expression(lexer)
 }
 
-fn parse_logical_or_expression(lexer: &mut Lexer) -> AstNode {
-    let mut operands = vec![parse_logical_and_expression(lexer)];
+fn parse_assignment_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let target = parse_ternary_expression(lexer)?;
 
-    while lexer.peek() == Some('|') {
-        lexer.advance();
-
-        if lexer.peek() == Some('|') {
-            lexer.advance();
-            operands.push(parse_logical_and_expression(lexer));
-        } else {
-            break;
-        }
+    lexer.skip_blanks();
+    if !is_lvalue(&target) {
+        return Ok(target);
     }
-
-    if operands.len() == 1 {
-        operands.pop().unwrap()
-    } else {
-        AstNode::LogicalOrExpression(Box::new(operands.remove(0)), operands)
+    // `x += 1` is `x = x + 1`, keeping the operator without its `=`.
+    if let Some(operator) = lexer.consume_operator(&["+=", "-=", "**=", "*=", "/=", "%=", "^="]) {
+        lexer.skip_whitespace();
+        let value = parse_assignment_expression(lexer)?;
+        let operator = operator.trim_end_matches('=').to_string();
+        return Ok(AstNode::CompoundAssignmentExpression(
+            Box::new(target),
+            operator,
+            Box::new(value),
+        ));
+    }
+    if lexer.peek() != Some('=') || lexer.peek_ahead(1) == Some('=') {
+        return Ok(target);
     }
+    lexer.advance();
+    lexer.skip_whitespace();
+
+    let value = parse_assignment_expression(lexer)?;
+    Ok(AstNode::AssignmentExpression(Box::new(target), Box::new(value)))
 }
 
-fn parse_logical_and_expression(lexer: &mut Lexer) -> AstNode {
-    let mut operands = vec![parse_inclusive_or_expression(lexer)];
+// An operator character followed by `=`, as in `+=`, ends the operand
+// before it rather than starting a binary expression.
+fn at_compound_assignment(lexer: &Lexer) -> bool {
+    lexer.peek_ahead(1) == Some('=')
+}
 
-    while lexer.peek() == Some('&') {
-        lexer.advance();
-        operands.push(parse_inclusive_or_expression(lexer));
-    }
+fn is_lvalue(node: &AstNode) -> bool {
+    matches!(
+        node,
+        AstNode::Variable(_) | AstNode::ArrayElement(_, _) | AstNode::FieldReference(_)
+    )
+}
 
-    if operands.len() == 1 {
-        operands.pop().unwrap()
-    } else {
-        AstNode::LogicalAndExpression(
-            Box::new(operands.remove(0)),
-            operands,
-        )
+fn parse_ternary_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let condition = parse_logical_or_expression(lexer)?;
+
+    lexer.skip_blanks();
+    if lexer.peek() != Some('?') {
+        return Ok(condition);
     }
+    lexer.advance();
+    lexer.skip_whitespace();
+
+    let if_true = parse_ternary_expression(lexer)?;
+
+    lexer.skip_whitespace();
+    lexer.expect(':')?;
+    lexer.skip_whitespace();
+
+    let if_false = parse_ternary_expression(lexer)?;
+
+    Ok(AstNode::TernaryExpression(Box::new(condition), Box::new(if_true), Box::new(if_false)))
 }
 
-fn parse_inclusive_or_expression(lexer: &mut Lexer) -> AstNode {
-    let mut operands = vec![parse_exclusive_or_expression(lexer)];
-    while lexer.peek() == Some('|') {
+fn parse_logical_or_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut operands = vec![parse_logical_and_expression(lexer)?];
+
+    while lexer.starts_with("||") {
+        lexer.advance();
         lexer.advance();
-        operands.push(parse_exclusive_or_expression(lexer));
+        lexer.skip_whitespace();
+        operands.push(parse_logical_and_expression(lexer)?);
     }
+
     if operands.len() == 1 {
-        operands.pop().unwrap()
+        Ok(operands.pop().unwrap())
     } else {
-        AstNode::InclusiveOrExpression(Box::new(operands.remove(0)), operands)
+        Ok(AstNode::LogicalOrExpression(Box::new(operands.remove(0)), operands))
     }
 }
 
-fn parse_exclusive_or_expression(lexer: &mut Lexer) -> AstNode {
-    let mut operands = vec![parse_and_expression(lexer)];
-    while lexer.peek() == Some('^') {
+fn parse_logical_and_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut operands = vec![parse_and_expression(lexer)?];
+
+    while lexer.peek() == Some('&') {
         lexer.advance();
-        operands.push(parse_and_expression(lexer));
+        operands.push(parse_and_expression(lexer)?);
     }
+
     if operands.len() == 1 {
-        operands.pop().unwrap()
+        Ok(operands.pop().unwrap())
     } else {
-        AstNode::ExclusiveOrExpression(Box::new(operands.remove(0)), operands)
+        Ok(AstNode::LogicalAndExpression(
+            Box::new(operands.remove(0)),
+            operands,
+        ))
     }
 }
 
-fn parse_and_expression(lexer: &mut Lexer) -> AstNode {
-    let mut operands = vec![parse_equality_expression(lexer)];
+fn parse_and_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut operands = vec![parse_membership_expression(lexer)?];
 
     while lexer.peek() == Some('&') {
         lexer.advance();
 
         if lexer.peek() == Some('&') {
             lexer.advance();
-            operands.push(parse_equality_expression(lexer));
+            lexer.skip_whitespace();
+            operands.push(parse_membership_expression(lexer)?);
         } else {
             break;
         }
     }
 
     if operands.len() == 1 {
-        operands.pop().unwrap()
+        Ok(operands.pop().unwrap())
     } else {
-        AstNode::AndExpression(Box::new(operands.remove(0)), operands)
+        Ok(AstNode::AndExpression(Box::new(operands.remove(0)), operands))
     }
 }
 
-fn parse_equality_expression(lexer: &mut Lexer) -> AstNode {
-    let mut operands = vec![parse_relational_expression(lexer)];
-
-    while lexer.peek() == Some('=') || lexer.peek() == Some('!') {
-        lexer.advance();
-        lexer.advance();
-
-        let first_operand = operands.pop().unwrap();
-
-        operands.push(AstNode::EqualityExpression(
-            Box::new(first_operand),
-            lexer.previous_lexeme().to_string(),
-            Box::new(parse_relational_expression(lexer)),
-        ));
+fn parse_membership_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut expression = parse_match_expression(lexer)?;
 
+    lexer.skip_blanks();
+    while lexer.consume_keyword("in") {
+        lexer.skip_blanks();
+        let array = parse_name(lexer, "an array name")?;
+        expression = AstNode::InExpression(Box::new(expression), array);
+        lexer.skip_blanks();
     }
 
-    if operands.len() == 1 {
-        operands.pop().unwrap()
-    } else {
-        AstNode::EqualityExpression(
-            Box::new(operands.remove(0)),
-            "".to_string(),
-            Box::new(operands.remove(0)),
-        )
-    }
+    Ok(expression)
 }
 
+// `~` and `!~` bind more loosely than comparisons, so `$1 == 2 ~ 1` matches
+// the comparison's result.
+fn parse_match_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut expression = parse_equality_expression(lexer)?;
 
-fn parse_relational_expression(lexer: &mut Lexer) -> AstNode {
-    let mut operands = vec![parse_shift_expression(lexer)];
-
-    while matches!(
-        lexer.peek(),
-        Some('<') | Some('>') | Some('=')
-    ) {
-        let operator = lexer.peek().unwrap_or_default().to_string();
+    lexer.skip_blanks();
+    while let Some(operator) = lexer.consume_operator(&["!~", "~"]) {
+        expression = AstNode::MatchExpression(
+            Box::new(expression),
+            operator,
+            Box::new(parse_equality_expression(lexer)?),
+        );
+        lexer.skip_blanks();
+    }
 
-        lexer.advance();
+    Ok(expression)
+}
 
-        if matches!(lexer.peek(), Some('=') | Some('>')) {
-            lexer.advance();
-        }
-        
-        let first_operand = operands.pop().unwrap();
+fn parse_equality_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut expression = parse_relational_expression(lexer)?;
 
-        operands.push(AstNode::RelationalExpression(
-            Box::new(first_operand),
+    lexer.skip_blanks();
+    while let Some(operator) = lexer.consume_operator(&["==", "!="]) {
+        expression = AstNode::EqualityExpression(
+            Box::new(expression),
             operator,
-            Box::new(parse_shift_expression(lexer)),
-        ));
+            Box::new(parse_relational_expression(lexer)?),
+        );
+        lexer.skip_blanks();
     }
 
-    if operands.len() == 1 {
-        operands.pop().unwrap()
-    } else {
-        AstNode::RelationalExpression(
-            Box::new(operands.remove(0)),
-            "".to_string(),
-            Box::new(operands.remove(0)),
-        )
-    }
+    Ok(expression)
 }
 
-fn parse_shift_expression(lexer: &mut Lexer) -> AstNode {
-    let mut operands = vec![parse_additive_expression(lexer)];
+fn parse_relational_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut expression = parse_concatenation_expression(lexer)?;
+    expression = parse_command_getline(lexer, expression)?;
 
-    while matches!(
-        lexer.peek(),
-        Some('<') | Some('>')
-    ) {
-        let operator = lexer.peek().unwrap_or_default().to_string();
-
-        lexer.advance();
+    lexer.skip_blanks();
+    while !lexer.at_redirection() {
+        let Some(operator) = lexer.consume_operator(&["<=", ">=", "<", ">"]) else {
+            break;
+        };
+        expression = AstNode::RelationalExpression(
+            Box::new(expression),
+            operator,
+            Box::new(parse_concatenation_expression(lexer)?),
+        );
+        lexer.skip_blanks();
+    }
 
-        if matches!(lexer.peek(), Some('<') | Some('>')) {
-            lexer.advance();
-        }
+    Ok(expression)
+}
 
-        let first_operand = operands.pop().unwrap();
+fn parse_concatenation_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut expression = parse_additive_expression(lexer)?;
 
-        operands.push(AstNode::ShiftExpression(
-            Box::new(first_operand),
-            operator,
-            Box::new(parse_additive_expression(lexer)),
-        ));
+    lexer.skip_blanks();
+    while starts_concatenation_operand(lexer) {
+        expression = AstNode::ConcatenationExpression(
+            Box::new(expression),
+            Box::new(parse_additive_expression(lexer)?),
+        );
+        lexer.skip_blanks();
     }
 
-    if operands.len() == 1 {
-        operands.pop().unwrap()
-    } else {
-        AstNode::ShiftExpression(
-            Box::new(operands.remove(0)),
-            "".to_string(),
-            Box::new(operands.remove(0)),
-        )
+    Ok(expression)
+}
+
+fn starts_concatenation_operand(lexer: &Lexer) -> bool {
+    match lexer.peek() {
+        Some(ch) if ch.is_alphabetic() || ch == '_' => !lexer.peek_keyword("in"),
+        Some('"') | Some('(') | Some('$') | Some('@') => true,
+        _ => starts_number(lexer),
     }
 }
 
-fn parse_additive_expression(lexer: &mut Lexer) -> AstNode {
-    let mut operands = vec![parse_multiplicative_expression(lexer)];
+fn parse_additive_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut operands = vec![parse_multiplicative_expression(lexer)?];
 
     while matches!(
         lexer.peek(),
         Some('+') | Some('-')
-    ) {
+    ) && !at_compound_assignment(lexer)
+    {
         let operator = lexer.peek().unwrap_or_default().to_string();
 
         lexer.advance();
@@ -696,29 +976,31 @@ fn parse_additive_expression(lexer: &mut Lexer) -> AstNode {
         operands.push(AstNode::AdditiveExpression(
             Box::new(first_operand),
             operator,
-            Box::new(parse_multiplicative_expression(lexer)),
+            Box::new(parse_multiplicative_expression(lexer)?),
         ));
     }
 
     if operands.len() == 1 {
-        operands.pop().unwrap()
+        Ok(operands.pop().unwrap())
     } else {
-        AstNode::AdditiveExpression(
+        Ok(AstNode::AdditiveExpression(
             Box::new(operands.remove(0)),
             "".to_string(),
             Box::new(operands.remove(0)),
-        )
+        ))
     }
 }
 
 
-fn parse_multiplicative_expression(lexer: &mut Lexer) -> AstNode {
-    let mut operands = vec![parse_primary_expression(lexer)];
+fn parse_multiplicative_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut operands = vec![parse_unary_expression(lexer)?];
 
     while matches!(
         lexer.peek(),
         Some('*') | Some('/') | Some('%')
-    ) {
+    ) && !at_compound_assignment(lexer)
+        && !lexer.starts_with("**")
+    {
         let operator = lexer.peek().unwrap_or_default().to_string();
 
         lexer.advance();
@@ -728,108 +1010,417 @@ fn parse_multiplicative_expression(lexer: &mut Lexer) -> AstNode {
         operands.push(AstNode::MultiplicativeExpression(
             Box::new(first_operand),
             operator,
-            Box::new(parse_primary_expression(lexer)),
+            Box::new(parse_unary_expression(lexer)?),
         ));
     }
 
     if operands.len() == 1 {
-        operands.pop().unwrap()
+        Ok(operands.pop().unwrap())
     } else {
-        AstNode::MultiplicativeExpression(
+        Ok(AstNode::MultiplicativeExpression(
             Box::new(operands.remove(0)),
             "".to_string(),
             Box::new(operands.remove(0)),
-        )
+        ))
     }
 }
 
+fn parse_unary_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.skip_blanks();
 
-fn parse_primary_expression(lexer: &mut Lexer) -> AstNode {
-    if lexer.peek().unwrap().is_alphabetic() {
-        parse_variable(lexer)
-    } else if lexer.peek().unwrap().is_alphabetic() {
-        parse_variable(lexer)
-    } else if lexer.peek().unwrap().is_ascii_digit() {
+    let operator = match (lexer.peek(), lexer.peek_ahead(1)) {
+        (Some('+'), Some('+')) => "++",
+        (Some('-'), Some('-')) => "--",
+        (Some('+'), _) => "+",
+        (Some('-'), _) => "-",
+        (Some('!'), _) => "!",
+        _ => return parse_exponent_expression(lexer),
+    };
+
+    for _ in 0..operator.len() {
+        lexer.advance();
+    }
+
+    let operand = parse_unary_expression(lexer)?;
+    Ok(AstNode::UnaryExpression(operator.to_string(), Box::new(operand)))
+}
+
+// `^` (or `**`) binds tighter than unary minus and groups to the right, so
+// `-2^2` is -4 and `2^3^2` is 512. Its right operand may carry a sign.
+fn parse_exponent_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let base = parse_postfix_expression(lexer)?;
+
+    lexer.skip_blanks();
+    if lexer.starts_with("^=") || lexer.starts_with("**=") {
+        return Ok(base);
+    }
+    if lexer.consume_operator(&["**", "^"]).is_none() {
+        return Ok(base);
+    }
+
+    let exponent = parse_unary_expression(lexer)?;
+    Ok(AstNode::ExponentExpression(Box::new(base), Box::new(exponent)))
+}
+
+fn parse_postfix_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let operand = parse_primary_expression(lexer)?;
+
+    lexer.skip_blanks();
+    let operator = match (lexer.peek(), lexer.peek_ahead(1)) {
+        (Some('+'), Some('+')) => "++",
+        (Some('-'), Some('-')) => "--",
+        _ => return Ok(operand),
+    };
+
+    lexer.advance();
+    lexer.advance();
+
+    Ok(AstNode::PostfixExpression(Box::new(operand), operator.to_string()))
+}
+
+fn parse_primary_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    if lexer.peek().is_some_and(|ch| ch.is_alphabetic()) {
+        parse_identifier_expression(lexer)
+    } else if starts_number(lexer) {
         parse_constant(lexer)
     } else if lexer.peek() == Some('"') {
         parse_string_literal(lexer)
+    } else if lexer.peek() == Some('/') {
+        parse_regex_literal(lexer)
+    } else if lexer.peek() == Some('$') {
+        parse_field_reference(lexer)
+    } else if lexer.peek() == Some('@') {
+        parse_indirect_function_call(lexer)
     } else if lexer.peek() == Some('(') {
+        parse_nested(lexer, |lexer| {
+            lexer.advance();
+            let expression = parse_expression(lexer)?;
+            if lexer.peek() == Some(',') {
+                return parse_grouped_membership(lexer, expression);
+            }
+            lexer.expect(')')?;
+            Ok(expression)
+        })
+    } else {
+        Err(lexer.error("an expression"))
+    }
+}
+
+fn parse_field_reference(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect('$')?;
+    Ok(AstNode::FieldReference(Box::new(parse_primary_expression(lexer)?)))
+}
+
+fn parse_grouped_membership(
+    lexer: &mut Lexer,
+    first_subscript: AstNode,
+) -> Result<AstNode, ParseError> {
+    let mut subscripts = vec![first_subscript];
+    while lexer.peek() == Some(',') {
         lexer.advance();
-        let expression = parse_expression(lexer);
-        assert_eq!(lexer.peek(), Some(')'));
+        lexer.skip_whitespace();
+        subscripts.push(parse_expression(lexer)?);
+    }
+    lexer.expect(')')?;
+    lexer.skip_blanks();
+    lexer.expect_keyword("in")?;
+    lexer.skip_blanks();
+    let array = parse_name(lexer, "an array name")?;
+    Ok(AstNode::InExpression(Box::new(AstNode::ExpressionList(subscripts)), array))
+}
+
+fn parse_identifier_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    // Bare `length` means `length($0)`; it only takes an argument when the
+    // parenthesis follows immediately, so `length " items"` concatenates.
+    if lexer.peek_keyword("length") && lexer.peek_ahead("length".len()) != Some('(') {
+        lexer.consume_keyword("length");
+        return Ok(AstNode::FunctionCall("length".to_string(), Box::new(None)));
+    }
+    if lexer.peek_keyword("getline") {
+        return parse_getline_expression(lexer);
+    }
+
+    let start = lexer.location();
+    parse_identifier(lexer);
+    let is_call = lexer.peek() == Some('(');
+    lexer.rewind(start);
+
+    if is_call {
+        parse_function_call(lexer)
+    } else {
+        parse_variable(lexer)
+    }
+}
+
+// `getline [lvalue] [< file]`. The file is a single primary expression, so
+// `getline line < dir "/" name` reads from `dir`, as in other awks.
+fn parse_getline_expression(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let target = parse_getline_target(lexer)?;
+
+    lexer.skip_blanks();
+    let source = if lexer.peek() == Some('<') && lexer.peek_ahead(1) != Some('=') {
         lexer.advance();
-        expression
+        lexer.skip_blanks();
+        let file = parse_primary_expression(lexer)?;
+        Some(Box::new(AstNode::Redirection("<".to_string(), Box::new(file))))
     } else {
-        panic!("Unexpected token while parsing primary expression")
+        None
+    };
+
+    Ok(AstNode::GetlineExpression(target, source))
+}
+
+// `cmd | getline [lvalue]`, which binds tighter than comparisons so that
+// `"cmd" | getline > 0` tests the status.
+fn parse_command_getline(lexer: &mut Lexer, mut command: AstNode) -> Result<AstNode, ParseError> {
+    loop {
+        lexer.skip_blanks();
+        if lexer.in_print || lexer.peek() != Some('|') || lexer.starts_with("||") {
+            return Ok(command);
+        }
+
+        let start = lexer.location();
+        lexer.advance();
+        lexer.skip_blanks();
+        if !lexer.peek_keyword("getline") {
+            lexer.rewind(start);
+            return Ok(command);
+        }
+
+        let target = parse_getline_target(lexer)?;
+        let source = AstNode::Redirection("|".to_string(), Box::new(command));
+        command = AstNode::GetlineExpression(target, Some(Box::new(source)));
     }
 }
 
-fn parse_variable(lexer: &mut Lexer) -> AstNode {
-    AstNode::Variable(parse_identifier(lexer))
+fn parse_getline_target(lexer: &mut Lexer) -> Result<Option<Box<AstNode>>, ParseError> {
+    lexer.expect_keyword("getline")?;
+    lexer.skip_blanks();
+
+    Ok(match lexer.peek() {
+        Some('$') => Some(Box::new(parse_field_reference(lexer)?)),
+        Some(ch) if ch.is_alphabetic() || ch == '_' => Some(Box::new(parse_variable(lexer)?)),
+        _ => None,
+    })
 }
 
-fn parse_constant(lexer: &mut Lexer) -> AstNode {
-    if lexer.peek().unwrap().is_ascii_digit() {
-        AstNode::Constant(Constant::IntegerLiteral(parse_integer_literal(lexer)))
-    } else if lexer.peek() == Some('.') {
-        AstNode::Constant(Constant::FloatingPointLiteral(
-            parse_floating_point_literal(lexer),
-        ))
+fn parse_variable(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let identifier = parse_name(lexer, "a variable name")?;
+    if lexer.peek() == Some('[') {
+        parse_subscripts(lexer, identifier)
     } else {
-        panic!("Unexpected token while parsing constant")
+        Ok(AstNode::Variable(identifier))
+    }
+}
+
+fn starts_number(lexer: &Lexer) -> bool {
+    match lexer.peek() {
+        Some(ch) if ch.is_ascii_digit() => true,
+        Some('.') => lexer.peek_ahead(1).is_some_and(|ch| ch.is_ascii_digit()),
+        _ => false,
     }
 }
 
-fn parse_integer_literal(lexer: &mut Lexer) -> String {
-    lexer.consume_digit_sequence()
+// `12` is an integer; `1.`, `.5`, `1e3` and `.25e1` are floating point.
+fn parse_constant(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    if !starts_number(lexer) {
+        return Err(lexer.error("a number"));
+    }
+    let literal = parse_floating_point_literal(lexer);
+    if literal.contains(['.', 'e', 'E']) {
+        Ok(AstNode::Constant(Constant::FloatingPointLiteral(literal)))
+    } else {
+        Ok(AstNode::Constant(Constant::IntegerLiteral(literal)))
+    }
 }
 
 fn parse_floating_point_literal(lexer: &mut Lexer) -> String {
     lexer.consume_floating_point_literal()
 }
 
-fn parse_string_literal(lexer: &mut Lexer) -> AstNode {
-    AstNode::Constant(Constant::StringLiteral(lexer.consume_string_literal()))
+fn escape_sequence(escaped: char) -> char {
+    match escaped {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        other => other,
+    }
 }
 
-fn parse_function_call(lexer: &mut Lexer) -> AstNode {
+/// Applies string-literal escape sequences to text given outside the
+/// program, such as `-v` assignments and `-F`.
+pub fn unescape(text: &str) -> String {
+    let mut value = String::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.next() {
+                Some(escaped) => value.push(escape_sequence(escaped)),
+                None => value.push('\\'),
+            },
+            ch => value.push(ch),
+        }
+    }
+    value
+}
+
+/// Splits a command-line `var=value` assignment, unescaping the value.
+/// Returns None when the text before `=` is not a valid variable name, in
+/// which case an operand is an input file instead.
+pub fn parse_assignment(assignment: &str) -> Option<(String, String)> {
+    let (name, value) = assignment.split_once('=')?;
+    let mut chars = name.chars();
+    let valid_start = chars.next().is_some_and(|ch| ch.is_ascii_alphabetic() || ch == '_');
+    if !valid_start || !chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_') {
+        return None;
+    }
+    Some((name.to_string(), unescape(value)))
+}
+
+fn parse_string_literal(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    Ok(AstNode::Constant(Constant::StringLiteral(lexer.consume_string_literal())))
+}
+
+fn parse_regex_literal(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    Ok(AstNode::RegexLiteral(lexer.consume_regex_literal()))
+}
+
+fn parse_function_call(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
     let identifier = parse_identifier(lexer);
-    assert_eq!(lexer.peek(), Some('('));
-    lexer.advance();
+    lexer.expect('(')?;
     let argument_list = if lexer.peek() != Some(')') {
-        Some(parse_argument_list(lexer))
+        Some(parse_nested(lexer, parse_argument_list)?)
     } else {
         None
     };
-    assert_eq!(lexer.peek(), Some(')'));
-    lexer.advance();
-    AstNode::FunctionCall(identifier, Box::new(argument_list))
+    lexer.expect(')')?;
+    Ok(AstNode::FunctionCall(identifier, Box::new(argument_list)))
 }
 
-fn parse_argument_list(lexer: &mut Lexer) -> AstNode {
-    let mut arguments = vec![parse_expression(lexer)];
+// `@name(args)` calls the function whose name is the value of `name`.
+fn parse_indirect_function_call(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    lexer.expect('@')?;
+    match parse_function_call(lexer)? {
+        AstNode::FunctionCall(variable_name, arguments) => {
+            Ok(AstNode::IndirectFunctionCall(variable_name, arguments))
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn parse_argument_list(lexer: &mut Lexer) -> Result<AstNode, ParseError> {
+    let mut arguments = vec![parse_expression(lexer)?];
     while lexer.peek() == Some(',') {
         lexer.advance();
-        arguments.push(parse_expression(lexer));
+        lexer.skip_whitespace();
+        arguments.push(parse_expression(lexer)?);
     }
-    AstNode::ArgumentList(arguments)
+    Ok(AstNode::ArgumentList(arguments))
 }
 
-fn parse_redirection(lexer: &mut Lexer) -> String {
-    lexer.consume_redirection()
+fn parse_redirection(lexer: &mut Lexer) -> Result<Option<Box<AstNode>>, ParseError> {
+    lexer.skip_blanks();
+    if !lexer.at_redirection() {
+        return Ok(None);
+    }
+    let Some(operator) = lexer.consume_operator(&[">>", ">", "|"]) else {
+        return Ok(None);
+    };
+    lexer.skip_blanks();
+    let target = parse_concatenation_expression(lexer)?;
+    Ok(Some(Box::new(AstNode::Redirection(operator, Box::new(target)))))
+}
+
+// Parentheses, subscripts and call arguments are ordinary expressions even
+// inside a `print` list.
+fn parse_nested<T>(lexer: &mut Lexer, parse: impl FnOnce(&mut Lexer) -> T) -> T {
+    let in_print = std::mem::replace(&mut lexer.in_print, false);
+    let parsed = parse(lexer);
+    lexer.in_print = in_print;
+    parsed
 }
 
 fn parse_identifier(lexer: &mut Lexer) -> String {
     lexer.consume_identifier()
 }
 
-fn parse_format_string(lexer: &mut Lexer) -> AstNode {
-    assert_eq!(lexer.peek(), Some('"'));
-    lexer.advance();
-    let mut format_string = String::new();
-    while lexer.peek() != Some('"') {
-        format_string.push(lexer.consume_character());
+fn parse_name(lexer: &mut Lexer, expected: &str) -> Result<String, ParseError> {
+    let name = parse_identifier(lexer);
+    if name.is_empty() {
+        return Err(lexer.error(expected));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(source: &str) -> ParseError {
+        match parse(source) {
+            Err(error) => error,
+            Ok(program) => panic!("`{}` parsed as {:?}", source, program),
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_returned_with_their_location() {
+        let error = parse_error("BEGIN {\n  x = 1\n  print (\n}");
+        assert_eq!((error.span.line, error.span.column), (3, 10));
+        assert_eq!(error.found, Some('\n'));
+        assert_eq!(
+            error.to_string(),
+            "syntax error at line 3, column 10: expected an expression, found newline"
+        );
+    }
+
+    #[test]
+    fn a_closing_brace_terminates_a_statement() {
+        assert!(parse("{ for(i=1;i<=NF;i++) { s += $i } print s }").is_ok());
+        let program = parse("BEGIN { if (1) { print 1 } print 2 }").unwrap();
+        let listing = format!("{:?}", program);
+        assert_eq!(listing.matches("PrintStatement").count(), 2, "{}", listing);
+    }
+
+    #[test]
+    fn a_parenthesized_print_list_is_not_a_membership_test() {
+        let program = parse("BEGIN { print(\"a\",\"b\") }").unwrap();
+        let listing = format!("{:?}", program);
+        assert!(!listing.contains("InExpression"), "{}", listing);
+        assert_eq!(listing.matches("StringLiteral").count(), 2, "{}", listing);
+        assert!(parse("BEGIN { print (\"a\",\"b\") > \"f\" }").is_ok());
+        assert!(parse("BEGIN { print (1, 2) in a }").is_ok());
+    }
+
+    #[test]
+    fn tilde_is_not_a_unary_operator() {
+        assert_eq!(parse_error("BEGIN { x = ~1 }").found, Some('~'));
+    }
+
+    #[test]
+    fn comments_and_continuation_newlines_are_whitespace() {
+        assert!(parse("BEGIN { print 1, # first\n 2 } # end").is_ok());
+        assert!(parse("BEGIN { if (a &&\n b ||\n c) x = f(1,\n 2) }").is_ok());
+        assert!(parse("BEGIN { x = \"#\" }").is_ok());
+    }
+
+    #[test]
+    fn unterminated_programs_report_the_end() {
+        let error = parse_error("BEGIN { x = 1");
+        assert_eq!(error.found, None);
+        assert_eq!(error.span.start, "BEGIN { x = 1".len());
+    }
+
+    #[test]
+    fn float_literals_are_floating_point_constants() {
+        for literal in ["1.5", ".25", "1e3", "2.5E-1", "1."] {
+            let mut lexer = Lexer::new(literal);
+            match parse_constant(&mut lexer) {
+                Ok(AstNode::Constant(Constant::FloatingPointLiteral(parsed))) => {
+                    assert_eq!(parsed, literal)
+                }
+                other => panic!("`{}` parsed as {:?}", literal, other),
+            }
+        }
     }
-    lexer.advance();
-    AstNode::Constant(Constant::StringLiteral(format_string))
 }
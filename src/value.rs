@@ -1,57 +1,430 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
 
-use std::io::prelude::*;
-use std::io::{Read, Write};
 use std::ops::*;
-use std::process::{Command, Stdio};
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Mutex;
 
 use std::cmp::*;
 
-use std::f64::consts::{E, PI};
 
 
-use rand::{Rng, SeedableRng};
-use regex::Regex;
+use regex::{Captures, Regex};
 
-use crate::awkio::AwkIO;
 use crate::exit_err;
+use crate::format::{format, DEFAULT_NUMBER_FORMAT};
+
+const MAX_SLURP_SIZE: u64 = 64 * 1024 * 1024;
+
+static BIGNUM_MODE: AtomicBool = AtomicBool::new(false);
+static CHARACTERS_AS_BYTES: AtomicBool = AtomicBool::new(false);
+static LAST_ERROR: Mutex<String> = Mutex::new(String::new());
+
+#[cfg(unix)]
+fn exit_status_code(status: ExitStatus) -> i64 {
+    use std::os::unix::process::ExitStatusExt;
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code as i64,
+        (None, Some(signal)) => 256 + signal as i64,
+        (None, None) => -1,
+    }
+}
+
+#[cfg(not(unix))]
+fn exit_status_code(status: ExitStatus) -> i64 {
+    status.code().map_or(-1, |code| code as i64)
+}
+
+pub fn set_bignum_mode(enabled: bool) {
+    BIGNUM_MODE.store(enabled, AtomicOrdering::Relaxed);
+}
+
+pub fn bignum_mode() -> bool {
+    BIGNUM_MODE.load(AtomicOrdering::Relaxed)
+}
+
+/// gawk's `-b`: `length`, `substr` and `index` count bytes rather than
+/// characters, and `printf "%c"` treats a number as a byte value.
+pub fn set_characters_as_bytes(enabled: bool) {
+    CHARACTERS_AS_BYTES.store(enabled, AtomicOrdering::Relaxed);
+}
+
+pub fn characters_as_bytes() -> bool {
+    CHARACTERS_AS_BYTES.load(AtomicOrdering::Relaxed)
+}
+
+// In byte mode a string may hold bytes that are not valid UTF-8, such as one
+// written by `printf "%c"` or half a character cut off by `substr`. Each is
+// kept as a character in the last private-use plane and written out as the
+// byte itself.
+const RAW_BYTE_BASE: u32 = 0x10_FF00;
+
+pub fn raw_byte(byte: u8) -> char {
+    if byte.is_ascii() {
+        char::from(byte)
+    } else {
+        char::from_u32(RAW_BYTE_BASE + byte as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+    }
+}
+
+fn raw_byte_value(character: char) -> Option<u8> {
+    let byte = (character as u32).checked_sub(RAW_BYTE_BASE)?;
+    (byte >= 0x80).then_some(byte as u8)
+}
+
+// Valid UTF-8 in `bytes` becomes characters and anything else raw bytes.
+fn string_from_bytes(bytes: &[u8]) -> String {
+    let mut string = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        string.push_str(chunk.valid());
+        string.extend(chunk.invalid().iter().map(|&byte| raw_byte(byte)));
+    }
+    string
+}
+
+/// The bytes that `s` stands for: its UTF-8, except that in byte mode raw
+/// bytes are themselves.
+pub fn string_bytes(s: &str) -> Cow<'_, [u8]> {
+    if !characters_as_bytes() || !s.chars().any(|c| raw_byte_value(c).is_some()) {
+        return Cow::Borrowed(s.as_bytes());
+    }
+
+    let mut bytes = Vec::with_capacity(s.len());
+    for character in s.chars() {
+        match raw_byte_value(character) {
+            Some(byte) => bytes.push(byte),
+            None => bytes.extend_from_slice(character.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Cow::Owned(bytes)
+}
+
+pub fn string_length(s: &str) -> usize {
+    if characters_as_bytes() {
+        string_bytes(s).len()
+    } else {
+        s.chars().count()
+    }
+}
+
+// The table for the reflected CRC-32 polynomial used by zlib, gzip and PNG.
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let crc = bytes.iter().fold(u32::MAX, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    });
+    !crc
+}
+
+// The 1-based position of `pattern` in `source`, or 0 when it is absent.
+fn string_position(source: &str, pattern: &str) -> i64 {
+    match source.find(pattern) {
+        Some(offset) => string_length(&source[..offset]) as i64 + 1,
+        None => 0,
+    }
+}
+
+/// Splits `input` the way FS and `split`'s separator do: a single space
+/// splits on runs of blanks and ignores them at either end, an empty
+/// separator splits into characters, any other single character is taken
+/// literally and a longer separator is a regular expression.
+pub fn split_fields(input: &str, separator: &str) -> Vec<String> {
+    match separator.chars().count() {
+        0 => input.chars().map(String::from).collect(),
+        1 if separator == " " => input.split_whitespace().map(String::from).collect(),
+        1 => input.split(separator).map(String::from).collect(),
+        _ => split_by_regex(input, separator),
+    }
+}
+
+fn split_by_regex(input: &str, pattern: &str) -> Vec<String> {
+    match get_or_compile(pattern) {
+        Ok(regex) => regex.split(input).map(String::from).collect(),
+        Err(_) => {
+            exit_err!("Invalid field separator `{}`", pattern);
+        }
+    }
+}
+
+/// `sub` and `gsub`: replaces the first match of `regex` in `input`, or
+/// every match when `global` is set, and returns the result with the
+/// number of replacements. In `replacement`, `&` is the matched text,
+/// `\&` a literal `&` and `\\` a backslash.
+pub fn substitute(input: &str, regex: &Regex, replacement: &str, global: bool) -> (String, usize) {
+    let mut count = 0;
+    let result = regex.replacen(input, if global { 0 } else { 1 }, |captures: &Captures| {
+        count += 1;
+        expand_replacement(replacement, &captures[0])
+    });
+    (result.into_owned(), count)
+}
+
+fn expand_replacement(replacement: &str, matched: &str) -> String {
+    let mut expanded = String::with_capacity(replacement.len());
+    let mut characters = replacement.chars().peekable();
+    while let Some(character) = characters.next() {
+        match character {
+            '\\' if matches!(characters.peek(), Some('&' | '\\')) => {
+                expanded.push(characters.next().unwrap());
+            }
+            '&' => expanded.push_str(matched),
+            character => expanded.push(character),
+        }
+    }
+    expanded
+}
+
+pub fn set_last_error(message: &str) {
+    *LAST_ERROR.lock().unwrap() = message.to_string();
+}
+
+/// The message of the last failure recorded with `set_last_error`, if any,
+/// clearing it.
+pub fn take_last_error() -> Option<String> {
+    let message = std::mem::take(&mut *LAST_ERROR.lock().unwrap());
+    (!message.is_empty()).then_some(message)
+}
+
+thread_local! {
+    static REGEX_CACHE: RefCell<HashMap<String, Regex>> = RefCell::new(HashMap::new());
+    #[cfg(test)]
+    static REGEX_COMPILATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Compiles `pattern`, reusing the regex from an earlier call with the same
+/// pattern so that matching on every record does not recompile it.
+pub fn get_or_compile(pattern: &str) -> Result<Regex, regex::Error> {
+    REGEX_CACHE.with(|cache| {
+        if let Some(regex) = cache.borrow().get(pattern) {
+            return Ok(regex.clone());
+        }
+
+        let regex = Regex::new(pattern)?;
+        #[cfg(test)]
+        REGEX_COMPILATIONS.with(|count| count.set(count.get() + 1));
+        cache.borrow_mut().insert(pattern.to_string(), regex.clone());
+        Ok(regex)
+    })
+}
+
+pub fn string_to_number(string: &str) -> f64 {
+    numeric_prefix(string).parse().unwrap_or(0.0)
+}
+
+/// Whether `string` is a number in its entirety, bar surrounding blanks,
+/// which is what makes a string compare numerically (AWK's "strnum").
+pub fn looks_numeric(string: &str) -> bool {
+    let trimmed = string.trim();
+    let prefix = numeric_prefix(trimmed);
+    !prefix.is_empty() && prefix.len() == trimmed.len()
+}
+
+// The longest leading part of `string`, after leading blanks, that reads as
+// a decimal number with an optional sign and exponent.
+fn numeric_prefix(string: &str) -> &str {
+    let string = string.trim_start();
+    let bytes = string.as_bytes();
+    let mut end = 0;
+
+    if end < bytes.len() && (bytes[end] == b'+' || bytes[end] == b'-') {
+        end += 1;
+    }
+
+    let digits_start = end;
+    while end < bytes.len() && bytes[end].is_ascii_digit() {
+        end += 1;
+    }
+    if end < bytes.len() && bytes[end] == b'.' {
+        end += 1;
+        while end < bytes.len() && bytes[end].is_ascii_digit() {
+            end += 1;
+        }
+    }
+    if end == digits_start || &string[digits_start..end] == "." {
+        return "";
+    }
+
+    if end < bytes.len() && (bytes[end] == b'e' || bytes[end] == b'E') {
+        let mut exponent_end = end + 1;
+        if exponent_end < bytes.len() && matches!(bytes[exponent_end], b'+' | b'-') {
+            exponent_end += 1;
+        }
+        if exponent_end < bytes.len() && bytes[exponent_end].is_ascii_digit() {
+            while exponent_end < bytes.len() && bytes[exponent_end].is_ascii_digit() {
+                exponent_end += 1;
+            }
+            end = exponent_end;
+        }
+    }
+
+    &string[..end]
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Number(i64),
+    BigNumber(i128),
     Float(f64),
-    Instruction(usize),
-    Identifier(String),
-    AssociativeIdentifier(String, String),
     StringLiteral(String),
+    /// A string that came from input (a field, a `split` element, ARGV,
+    /// ENVIRON or a command-line assignment). It compares as a number when
+    /// it looks like one, whereas a string constant always compares as text.
+    StrNum(String),
     RegexPattern(String),
     Bool(bool),
+    Uninitialized,
     Command(String, Vec<String>),
-    ExecResult(String, std::process::ExitStatus),
+    /// A set of elements built outside the VM, such as `split`'s result or
+    /// ARGV, before they are moved into an array.
     ArrayLiteral(HashMap<String, Box<Value>>),
-    FilePath(String),
-    AwkIO(AwkIO),
+    /// An AWK array: a handle into the VM's array store. Arrays are not
+    /// values, so a handle only ever names an array or passes it by
+    /// reference to a function; it cannot be assigned or used as a scalar.
+    Array(usize),
 }
 
 impl Value {
-    pub fn get_string(&self) -> Option<String> {
-        if let Self::StringLiteral(s) = self {
-            return Some(s.clone());
-        }
-        None
+    /// Wraps text read from input as a numeric string.
+    pub fn from_input(text: impl Into<String>) -> Value {
+        Value::StrNum(text.into())
     }
 
     pub fn is_string(&self) -> bool {
-        if let Self::StringLiteral(_) = self {
-            return true;
+        matches!(self, Self::StringLiteral(_) | Self::StrNum(_))
+    }
+
+    pub fn is_numeric_string(&self) -> bool {
+        matches!(self, Self::StrNum(s) if looks_numeric(s))
+    }
+
+    pub fn wide_integer(&self) -> Option<i128> {
+        match self {
+            Value::Number(n) => Some(*n as i128),
+            Value::Bool(b) => Some(*b as i128),
+            Value::BigNumber(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// The value as an integer without a detour through `f64`, when it is
+    /// one: an integer number, or a string reading as a plain integer such
+    /// as `"9007199254740993"`. `printf "%d"` uses this to stay exact.
+    pub fn exact_integer(&self) -> Option<i128> {
+        match self {
+            Value::StringLiteral(s) | Value::StrNum(s) => {
+                let digits = numeric_prefix(s);
+                if digits.contains(['.', 'e', 'E']) {
+                    return None;
+                }
+                digits.strip_prefix('+').unwrap_or(digits).parse().ok()
+            }
+            other => other.wide_integer(),
+        }
+    }
+
+    pub fn from_wide_integer(n: i128) -> Value {
+        match i64::try_from(n) {
+            Ok(n) => Value::Number(n),
+            Err(_) => Value::BigNumber(n),
+        }
+    }
+
+    // Uninitialized values and booleans take part in arithmetic as 0 and 1,
+    // in float form when the other operand is a float.
+    fn arithmetic_operand(&self, other: &Value) -> Value {
+        let number = match self {
+            Value::Uninitialized => 0,
+            Value::Bool(b) => *b as i64,
+            value => return value.clone(),
+        };
+
+        if matches!(other, Value::Float(_)) {
+            Value::Float(number as f64)
+        } else {
+            Value::Number(number)
+        }
+    }
+
+    fn bignum_arithmetic(
+        &self,
+        other: &Value,
+        checked_op: fn(i128, i128) -> Option<i128>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Option<Value> {
+        if !bignum_mode() {
+            return None;
+        }
+
+        let (a, b) = (self.wide_integer()?, other.wide_integer()?);
+        match checked_op(a, b) {
+            Some(n) => Some(Value::from_wide_integer(n)),
+            None => Some(Value::Float(float_op(a as f64, b as f64))),
+        }
+    }
+
+    pub fn to_number(&self) -> f64 {
+        match self {
+            Value::Number(n) => *n as f64,
+            Value::BigNumber(n) => *n as f64,
+            Value::Float(f) => *f,
+            Value::Bool(b) => *b as i64 as f64,
+            Value::StringLiteral(s) | Value::StrNum(s) => string_to_number(s),
+            Value::Uninitialized => 0.0,
+            _ => 0.0,
+        }
+    }
+
+    /// The string form of the value, with non-integral numbers rendered
+    /// through `number_format` (CONVFMT, or OFMT for `print`).
+    pub fn to_string_with_format(&self, number_format: &str) -> String {
+        match self {
+            Value::Float(n) if n.fract() != 0.0 || n.abs() >= 1e16 => {
+                format(number_format, &[Value::Float(*n)])
+            }
+            value => value.to_string(),
+        }
+    }
+
+    /// The key an array subscript refers to. Integral numbers key as plain
+    /// integers, so `a[1]`, `a["1"]` and `a[4/2 - 1]` are one element; other
+    /// numbers use `conversion_format` (CONVFMT).
+    pub fn to_subscript_key(&self, conversion_format: &str) -> String {
+        match self {
+            Value::Float(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => {
+                (*n as i64).to_string()
+            }
+            value => value.to_string_with_format(conversion_format),
         }
-        false
     }
 
     pub fn is_truthy(&self) -> bool {
         match self {
-            Value::Number(n) => *n > 0,
+            Value::Number(n) => *n != 0,
+            Value::BigNumber(n) => *n != 0,
+            Value::Float(f) => *f != 0.0,
             Value::StringLiteral(s) => !s.is_empty(),
+            value @ Value::StrNum(s) if value.is_numeric_string() => string_to_number(s) != 0.0,
+            Value::StrNum(s) => !s.is_empty(),
             Value::Bool(b) => *b,
             _ => false,
         }
@@ -62,35 +435,62 @@ impl Value {
     }
 
     pub fn add(&self, other: &Value) -> Option<Value> {
+        if let Some(sum) = self.bignum_arithmetic(other, i128::checked_add, |a, b| a + b) {
+            return Some(sum);
+        }
+
         match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Number(a + b)),
+            (Value::Uninitialized | Value::Bool(_), _)
+            | (_, Value::Uninitialized | Value::Bool(_)) => {
+                Value::add(&self.arithmetic_operand(other), &other.arithmetic_operand(self))
+            }
+            (Value::Number(a), Value::Number(b)) => Some(
+                a.checked_add(*b).map_or(Value::Float(*a as f64 + *b as f64), Value::Number),
+            ),
             (Value::Float(a), Value::Float(b)) => Some(Value::Float(a + b)),
-            (Value::StringLiteral(ref a), Value::StringLiteral(ref b)) => {
+            (
+                Value::StringLiteral(ref a) | Value::StrNum(ref a),
+                Value::StringLiteral(ref b) | Value::StrNum(ref b),
+            ) => {
                 let mut concatenated = a.clone();
                 concatenated.push_str(b);
                 Some(Value::StringLiteral(concatenated))
             }
-            (Value::ArrayLiteral(ref a), Value::ArrayLiteral(ref b)) => {
-                let mut concatenated = a.clone();
-                let b_cloned = b.clone();
-                concatenated.extend(b_cloned);
-                Some(Value::ArrayLiteral(concatenated))
-            }
             _ => None,
         }
     }
 
     pub fn subtract(&self, other: &Value) -> Option<Value> {
+        if let Some(difference) = self.bignum_arithmetic(other, i128::checked_sub, |a, b| a - b) {
+            return Some(difference);
+        }
+
         match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Number(a - b)),
+            (Value::Uninitialized | Value::Bool(_), _)
+            | (_, Value::Uninitialized | Value::Bool(_)) => {
+                Value::subtract(&self.arithmetic_operand(other), &other.arithmetic_operand(self))
+            }
+            (Value::Number(a), Value::Number(b)) => Some(
+                a.checked_sub(*b).map_or(Value::Float(*a as f64 - *b as f64), Value::Number),
+            ),
             (Value::Float(a), Value::Float(b)) => Some(Value::Float(a - b)),
             _ => None,
         }
     }
 
     pub fn multiply(&self, other: &Value) -> Option<Value> {
+        if let Some(product) = self.bignum_arithmetic(other, i128::checked_mul, |a, b| a * b) {
+            return Some(product);
+        }
+
         match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Number(a * b)),
+            (Value::Uninitialized | Value::Bool(_), _)
+            | (_, Value::Uninitialized | Value::Bool(_)) => {
+                Value::multiply(&self.arithmetic_operand(other), &other.arithmetic_operand(self))
+            }
+            (Value::Number(a), Value::Number(b)) => Some(
+                a.checked_mul(*b).map_or(Value::Float(*a as f64 * *b as f64), Value::Number),
+            ),
             (Value::Float(a), Value::Float(b)) => Some(Value::Float(a * b)),
             _ => None,
         }
@@ -98,9 +498,14 @@ impl Value {
 
     pub fn divide(&self, other: &Value) -> Option<Value> {
         match (self, other) {
+            (Value::Uninitialized | Value::Bool(_), _)
+            | (_, Value::Uninitialized | Value::Bool(_)) => {
+                Value::divide(&self.arithmetic_operand(other), &other.arithmetic_operand(self))
+            }
             (Value::Number(a), Value::Number(b)) => {
                 if *b != 0 {
-                    Some(Value::Number(a / b))
+                    let quotient = a.checked_div(*b);
+                    Some(quotient.map_or(Value::Float(*a as f64 / *b as f64), Value::Number))
                 } else {
                     None
                 }
@@ -116,39 +521,44 @@ impl Value {
         }
     }
 
-    pub fn exponentiate(&self, other: &Value) -> Option<Value> {
+    pub fn modulo(&self, other: &Value) -> Option<Value> {
         match (self, other) {
-            (Value::Number(base), Value::Number(exponent)) => {
-                Some(Value::Number(base.pow(*exponent as u32)))
+            (Value::Number(a), Value::Number(b)) => {
+                (*b != 0).then(|| Value::Number(a.wrapping_rem(*b)))
             }
-            (Value::Float(base), Value::Float(exponent)) => {
-                Some(Value::Float(base.powf(*exponent)))
+            (Value::BigNumber(_), _) | (_, Value::BigNumber(_)) => {
+                let (a, b) = (self.wide_integer()?, other.wide_integer()?);
+                a.checked_rem(b).map(Value::from_wide_integer)
             }
+            (Value::Float(a), Value::Float(b)) => Some(Value::Float(a % b)),
             _ => None,
         }
     }
 
-    pub fn equals(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Bool(a == b)),
-            (Value::Float(a), Value::Float(b)) => Some(Value::Bool(a == b)),
-            (Value::StringLiteral(a), Value::StringLiteral(b)) => Some(Value::Bool(a == b)),
-            _ => Some(Value::Bool(false)),
+    pub fn exponentiate(&self, other: &Value) -> Option<Value> {
+        let checked_pow = |base: i128, exponent: i128| {
+            u32::try_from(exponent).ok().and_then(|exponent| base.checked_pow(exponent))
+        };
+        if let Some(power) = self.bignum_arithmetic(other, checked_pow, f64::powf) {
+            return Some(power);
         }
-    }
 
-    pub fn shift_left(&self, other: &Value) -> Option<Value> {
         match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Number(a << b)),
-            _ => None
+            (Value::Number(base), Value::Number(exponent)) => {
+                match u32::try_from(*exponent).ok().and_then(|e| base.checked_pow(e)) {
+                    Some(power) => Some(Value::Number(power)),
+                    None => Some(Value::Float((*base as f64).powf(*exponent as f64))),
+                }
+            }
+            (Value::Float(base), Value::Float(exponent)) => {
+                Some(Value::Float(base.powf(*exponent)))
+            }
+            _ => None,
         }
     }
 
-    pub fn shift_right(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Number(a >> b)),
-            _ => None,
-        }
+    pub fn equals(&self, other: &Value) -> Option<Value> {
+        Some(Value::Bool(self.compare(other) == Ordering::Equal))
     }
 
     pub fn not_equals(&self, other: &Value) -> Option<Value> {
@@ -156,51 +566,72 @@ impl Value {
     }
 
     pub fn greater_than(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Bool(a > b)),
-            (Value::Float(a), Value::Float(b)) => Some(Value::Bool(a > b)),
-            _ => Some(Value::Bool(false)),
-        }
+        Some(Value::Bool(self.compare(other) == Ordering::Greater))
     }
 
     pub fn greater_than_equals(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Bool(a >= b)),
-            (Value::Float(a), Value::Float(b)) => Some(Value::Bool(a >= b)),
-            _ => Some(Value::Bool(false)),
-        }
+        Some(Value::Bool(self.compare(other) != Ordering::Less))
     }
 
     pub fn less_than(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Bool(a < b)),
-            (Value::Float(a), Value::Float(b)) => Some(Value::Bool(a < b)),
-            _ => Some(Value::Bool(false)),
-        }
+        Some(Value::Bool(self.compare(other) == Ordering::Less))
     }
 
     pub fn less_than_equals(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Bool(a <= b)),
-            (Value::Float(a), Value::Float(b)) => Some(Value::Bool(a <= b)),
-            _ => Some(Value::Bool(false)),
+        Some(Value::Bool(self.compare(other) != Ordering::Greater))
+    }
+
+    /// Orders two values the way AWK's relational operators do: numerically
+    /// when neither side is a non-numeric string, otherwise by string form.
+    pub fn compare(&self, other: &Value) -> Ordering {
+        if let (Some(a), Some(b)) = (self.wide_integer(), other.wide_integer()) {
+            return a.cmp(&b);
+        }
+
+        if self.compares_numerically() && other.compares_numerically() {
+            self.to_number()
+                .partial_cmp(&other.to_number())
+                .unwrap_or(Ordering::Equal)
+        } else {
+            self.to_string().cmp(&other.to_string())
+        }
+    }
+
+    fn compares_numerically(&self) -> bool {
+        match self {
+            Value::Number(_)
+            | Value::BigNumber(_)
+            | Value::Float(_)
+            | Value::Bool(_)
+            | Value::Uninitialized => true,
+            value => value.is_numeric_string(),
         }
     }
 
     pub fn ere_match(&self, pattern: &Value) -> Option<Value> {
         match (self, pattern) {
-            (Value::StringLiteral(input), Value::RegexPattern(regex)) => {
-                let regex = regex::Regex::new(regex).ok()?;
+            (Value::StringLiteral(input) | Value::StrNum(input), Value::RegexPattern(regex)) => {
+                let regex = get_or_compile(regex).ok()?;
                 Some(Value::Bool(regex.is_match(input)))
             }
             _ => Some(Value::Bool(false)),
         }
     }
 
+    pub fn match_record(&self, record: &str) -> Option<Value> {
+        match self {
+            Value::RegexPattern(regex) => {
+                let regex = get_or_compile(regex).ok()?;
+                Some(Value::Number(regex.is_match(record) as i64))
+            }
+            _ => None,
+        }
+    }
+
     pub fn ere_non_match(&self, pattern: &Value) -> Option<Value> {
         match (self, pattern) {
-            (Value::StringLiteral(input), Value::RegexPattern(regex)) => {
-                let regex = regex::Regex::new(regex).ok()?;
+            (Value::StringLiteral(input) | Value::StrNum(input), Value::RegexPattern(regex)) => {
+                let regex = get_or_compile(regex).ok()?;
                 Some(Value::Bool(!regex.is_match(input)))
             }
             _ => Some(Value::Bool(false)),
@@ -235,431 +666,371 @@ impl Value {
         }
     }
 
-    pub fn bitwise_not(&self) -> Option<Value> {
-        match self {
-            Value::Number(n) => Some(Value::Number(!n)),
-            _ => None,
+    /// A command line run through the platform shell, as `system()` and
+    /// pipes expect.
+    pub fn shell_command(command_line: &str) -> Value {
+        if cfg!(windows) {
+            Value::Command("cmd".to_string(), vec!["/C".to_string(), command_line.to_string()])
+        } else {
+            Value::Command("sh".to_string(), vec!["-c".to_string(), command_line.to_string()])
         }
     }
 
-    pub fn make_negative(&mut self) -> Option<()> {
-        match self {
-            Value::Number(ref mut n) => {
-                *n = -*n;
-                Some(())
-            }
-            Value::Float(ref mut f) => {
-                *f = -*f;
-                Some(())
+    /// Runs a command with the interpreter's own stdin, stdout and stderr and
+    /// returns its exit status, or 256 plus the signal number when a signal
+    /// ended it.
+    pub fn run_command(&self) -> Option<Value> {
+        if let Value::Command(command, args) = self {
+            match Command::new(command).args(args).status() {
+                Ok(status) => Some(Value::Number(exit_status_code(status))),
+                Err(e) => {
+                    set_last_error(&format!("cannot run `{}`: {}", command, e));
+                    Some(Value::Number(-1))
+                }
             }
-            _ => None,
-        }
-    }
-
-    pub fn bitwise_and(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Number(a & b)),
-            _ => None,
-        }
-    }
-
-    pub fn bitwise_or(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Number(a | b)),
-            _ => None,
+        } else {
+            exit_err!("Value is not a command");
         }
     }
 
-    pub fn bitwise_xor(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Some(Value::Number(a ^ b)),
-            _ => None,
+    /// Splits the string form of the value into `array`, clearing it first,
+    /// and returns the number of elements, stored under keys 1 to n. A single
+    /// space separates on runs of blanks, ignoring leading and trailing ones;
+    /// an empty separator yields single characters; any other one-character
+    /// separator is literal; longer separators and regex patterns are EREs.
+    pub fn split(&self, separator: &Value, array: &mut Value) -> Option<Value> {
+        if !self.is_scalar() || !matches!(array, Value::ArrayLiteral(_) | Value::Uninitialized) {
+            return None;
         }
-    }
 
-    pub fn logical_or(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => {
-                Some(Value::Number(if a != &0 || b != &0 { 1 } else { 0 }))
+        let input = self.to_string();
+        let pieces: Vec<String> = match separator {
+            _ if input.is_empty() => vec![],
+            Value::RegexPattern(pattern) => split_by_regex(&input, pattern),
+            separator => split_fields(&input, &separator.to_string()),
+        };
+
+        let piece_count = pieces.len();
+        *array = Value::ArrayLiteral(
+            pieces
+                .into_iter()
+                .enumerate()
+                .map(|(index, piece)| {
+                    ((index + 1).to_string(), Box::new(Value::from_input(piece)))
+                })
+                .collect(),
+        );
+        Some(Value::Number(piece_count as i64))
+    }
+
+
+    /// Counts the characters of the value's string form; numbers are
+    /// converted with `conversion_format` (CONVFMT) first.
+    pub fn length(&self, conversion_format: &str) -> Option<Value> {
+        match self {
+            Value::StringLiteral(s) | Value::StrNum(s) => {
+                Some(Value::Number(string_length(s) as i64))
+            }
+            Value::ArrayLiteral(map) => Some(Value::Number(map.len() as i64)),
+            Value::Uninitialized => Some(Value::Number(0)),
+            scalar if scalar.is_scalar() => {
+                let text = scalar.to_string_with_format(conversion_format);
+                Some(Value::Number(string_length(&text) as i64))
             }
             _ => None,
         }
     }
 
-    pub fn logical_and(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => {
-                Some(Value::Number(if a != &0 && b != &0 { 1 } else { 0 }))
-            }
-            _ => None,
+    /// AWK's `substr(s, start[, length])`: positions are 1-based and
+    /// rounded, and only the part of the range inside the string is kept, so
+    /// `substr("hello", 0, 2)` is `"h"`.
+    pub fn substring(&self, start: f64, length: Option<f64>) -> Option<Value> {
+        let s = match self {
+            Value::StringLiteral(s) | Value::StrNum(s) => s,
+            _ => return None,
+        };
+
+        let size = string_length(s) as f64;
+        let start = start.round();
+        let end = match length {
+            Some(length) => start + length.round(),
+            None => size + 1.0,
+        };
+        let (first, last) = (start.max(1.0), end.min(size + 1.0));
+        if first >= last {
+            return Some(Value::StringLiteral(String::new()));
         }
-    }
 
-    pub fn exec_command(&self) -> Option<Value> {
-        if let Value::Command(command, args) = self {
-            let output = Command::new(command)
-                .args(args)
-                .stdout(Stdio::piped())
-                .spawn();
-
-            match output {
-                Ok(mut child) => {
-                    let mut buffer = String::new();
-                    child
-                        .stdout
-                        .take()
-                        .unwrap()
-                        .read_to_string(&mut buffer)
-                        .ok();
-
-                    let status = child.wait().ok()?;
-
-                    Some(Value::ExecResult(buffer, status))
-                }
-                Err(e) => {
-                    exit_err!("Unexpected error: {}", e);
-                }
-            }
+        let (skip, take) = ((first - 1.0) as usize, (last - first) as usize);
+        let substring = if characters_as_bytes() {
+            string_from_bytes(&string_bytes(s)[skip..skip + take])
         } else {
-            exit_err!("Value is not a command");
-        }
+            s.chars().skip(skip).take(take).collect()
+        };
+        Some(Value::StringLiteral(substring))
     }
 
-    pub fn r#match(&self, pattern: &Value) -> Option<Value> {
-        match (self, pattern) {
-            (Value::StringLiteral(input), Value::StringLiteral(regex_str)) => {
-                let regex = Regex::new(regex_str).ok()?;
-                Some(Value::Bool(regex.is_match(input)))
-            }
+    pub fn index_of(&self, target: &Value) -> Option<Value> {
+        match (self, target) {
+            (
+                Value::StringLiteral(source) | Value::StrNum(source),
+                Value::StringLiteral(pattern) | Value::StrNum(pattern),
+            ) => Some(Value::Number(string_position(source, pattern))),
             _ => None,
         }
     }
 
-    pub fn not_match(&self, pattern: &Value) -> Option<Value> {
-        Some(Value::Bool(self.r#match(pattern).unwrap().is_falsy()))
+    pub fn is_scalar(&self) -> bool {
+        matches!(
+            self,
+            Value::Number(_)
+                | Value::BigNumber(_)
+                | Value::Float(_)
+                | Value::Bool(_)
+                | Value::StringLiteral(_)
+                | Value::StrNum(_)
+                | Value::Uninitialized
+        )
     }
 
-    pub fn substitute(&mut self, regex: &Value, replacement: &Value) -> Option<()> {
-        match (self, regex, replacement) {
-            (
-                Value::StringLiteral(input),
-                Value::StringLiteral(regex_str),
-                Value::StringLiteral(replacement_str),
-            ) => {
-                let regex = Regex::new(regex_str).ok()?;
-                *input = regex.replace_all(input, replacement_str).to_string();
-                Some(())
-            }
-            _ => None,
-        }
-    }
-
-    pub fn gsub(&mut self, regex: &Value, replacement: &Value) -> Option<()> {
-        match (self, regex, replacement) {
-            (
-                Value::StringLiteral(input),
-                Value::StringLiteral(regex_str),
-                Value::StringLiteral(replacement_str),
-            ) => {
-                let regex = Regex::new(regex_str).ok()?;
-                *input = regex.replace_all(input, replacement_str).to_string();
-                Some(())
+    pub fn to_lower(&self) -> Option<Value> {
+        match self {
+            Value::StringLiteral(s) => Some(Value::StringLiteral(s.to_lowercase())),
+            scalar if scalar.is_scalar() => {
+                Some(Value::StringLiteral(scalar.to_string().to_lowercase()))
             }
             _ => None,
         }
     }
 
-    pub fn match_array(&self, regex: &Value, array: &Value) -> Option<Value> {
-        match (self, regex, array) {
-            (
-                Value::StringLiteral(_),
-                Value::RegexPattern(regex_str),
-                Value::ArrayLiteral(array_map),
-            ) => {
-                let regex = Regex::new(regex_str).ok()?;
-                let matches: Vec<_> = array_map
-                    .values()
-                    .filter(|v| v.is_string())
-                    .map(|v| v.get_string().unwrap())
-                    .filter(|s| regex.is_match(s))
-                    .collect();
-
-                Some(Value::Bool(!matches.is_empty()))
+    pub fn to_upper(&self) -> Option<Value> {
+        match self {
+            Value::StringLiteral(s) => Some(Value::StringLiteral(s.to_uppercase())),
+            scalar if scalar.is_scalar() => {
+                Some(Value::StringLiteral(scalar.to_string().to_uppercase()))
             }
             _ => None,
         }
     }
 
-    pub fn non_match_array(&self, regex: &Value, array: &Value) -> Option<Value> {
-        Some(Value::Bool(
-            !self.match_array(regex, array).unwrap().is_falsy(),
-        ))
+    /// The CRC-32 of the string's bytes, so `crc32("123456789")` is
+    /// 0xCBF43926.
+    pub fn crc32(&self) -> Option<Value> {
+        let checksum = match self {
+            Value::StringLiteral(s) | Value::StrNum(s) => crc32(s.as_bytes()),
+            scalar if scalar.is_scalar() => crc32(scalar.to_string().as_bytes()),
+            _ => return None,
+        };
+        Some(Value::Number(checksum as i64))
     }
 
-    pub fn pipe(&self, command: &Value) -> Option<Value> {
-        match (self, command) {
-            (Value::StringLiteral(input), Value::Command(cmd, args)) => {
-                let output = Command::new(cmd)
-                    .args(args)
-                    .stdin(Stdio::piped())
-                    .stdout(Stdio::piped())
-                    .spawn();
-
-                match output {
-                    Ok(mut child) => {
-                        let mut child_stdin = child.stdin.take().unwrap();
-                        child_stdin.write_all(input.as_bytes()).unwrap();
-
-                        let mut buffer = String::new();
-                        child
-                            .stdout
-                            .take()
-                            .unwrap()
-                            .read_to_string(&mut buffer)
-                            .unwrap();
-
-                        let _status = child.wait().unwrap();
-
-                        Some(Value::StringLiteral(buffer))
-                    }
-                    Err(e) => {
-                        exit_err!("Unexpected error: {}", e);
-                    }
-                }
-            }
-            _ => {
-                exit_err!("Invalid usage of pipe operator");
-            }
+    /// Removes the leading and trailing characters found in `set`, or
+    /// whitespace without one. Numbers are stripped as strings.
+    pub fn strip(&self, set: Option<&Value>) -> Option<Value> {
+        if !self.is_scalar() {
+            return None;
         }
-    }
-
-    pub fn rand(&self) -> Option<Value> {
-        match self {
-            Value::Number(_seed) => {
-                let mut rng = rand::thread_rng();
-                Some(Value::Float(rng.gen_range(0.0..1.0)))
-            }
-            Value::Float(_seed) => {
-                let mut rng = rand::thread_rng();
-                Some(Value::Float(rng.gen_range(0.0..1.0)))
-            }
-            _ => {
-                exit_err!("Invalid usage of rand function");
+        let source = self.to_string();
+        let stripped = match set {
+            None => source.trim(),
+            Some(set) if set.is_scalar() => {
+                let set = set.to_string();
+                source.trim_matches(|ch| set.contains(ch))
             }
-        }
+            Some(_) => return None,
+        };
+        Some(Value::StringLiteral(stripped.to_string()))
     }
 
-    pub fn srand(&self, seed: i32) -> Option<Value> {
+    /// Reverses a string by characters. Arrays are reversed by the VM,
+    /// which owns their elements.
+    pub fn reverse(&self) -> Option<Value> {
         match self {
-            Value::Number(_) => {
-                let mut rng = rand::rngs::StdRng::seed_from_u64(seed as u64);
-                Some(Value::Float(rng.gen_range(0.0..1.0)))
-            }
-            Value::Float(_) => {
-                let mut rng = rand::rngs::StdRng::seed_from_u64(seed as u64);
-                Some(Value::Float(rng.gen_range(0.0..1.0)))
+            Value::StringLiteral(s) | Value::StrNum(s) => {
+                Some(Value::StringLiteral(s.chars().rev().collect()))
             }
-            _ => {
-                exit_err!("Invalid usage of srand function");
+            scalar if scalar.is_scalar() => {
+                Some(Value::StringLiteral(scalar.to_string().chars().rev().collect()))
             }
+            _ => None,
         }
     }
 
-    pub fn index(&self, target: &Value) -> Option<Value> {
-        match (self, target) {
-            (Value::StringLiteral(source), Value::StringLiteral(pattern)) => {
-                if let Some(position) = source.find(pattern) {
-                    Some(Value::Number(position as i64 + 1))
-                } else {
-                    Some(Value::Number(0))
-                }
-            }
-            _ => {
-                exit_err!("Invalid usage of index function");
-            }
+    /// Splits `key=value` pairs such as `"a=1;b=2"` into an array of values
+    /// by key. Pairs are separated by `pair_separator` and split at the first
+    /// `key_value_separator`; a pair without one gets an empty value.
+    pub fn parse_key_values(
+        &self,
+        pair_separator: &str,
+        key_value_separator: &str,
+    ) -> Option<Value> {
+        if !self.is_scalar() || pair_separator.is_empty() || key_value_separator.is_empty() {
+            return None;
         }
-    }
-
-    pub fn split(&self, regex: &Value, array: &mut Value) -> Option<Value> {
-        match (self, regex, array) {
-            (
-                Value::StringLiteral(input),
-                Value::StringLiteral(regex_str),
-                Value::ArrayLiteral(array_map),
-            ) => {
-                if let Ok(regex) = regex::Regex::new(regex_str) {
-                    let split_values: Vec<_> = regex.split(input).map(|s| s.to_string()).collect();
 
-                    for (index, value) in split_values.iter().cloned().enumerate() {
-                        array_map.insert(index.to_string(), Box::new(Value::StringLiteral(value)));
-                    }
-
-                    Some(Value::Number(split_values.len() as i64))
-                } else {
-                    exit_err!("Invalid regular expression in split function");
-                }
-            }
-            _ => {
-                exit_err!("Invalid usage of split function");
-            }
-        }
+        let source = self.to_string();
+        let pairs = source
+            .split(pair_separator)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once(key_value_separator).unwrap_or((pair, ""));
+                (key.to_string(), Box::new(Value::from_input(value)))
+            })
+            .collect();
+        Some(Value::ArrayLiteral(pairs))
     }
 
-    pub fn sub(&mut self, regex: &Value, replacement: &Value) -> Option<Value> {
-        match (self, regex, replacement) {
-            (
-                Value::StringLiteral(input),
-                Value::StringLiteral(regex_str),
-                Value::StringLiteral(replacement_str),
-            ) => {
-                if let Ok(regex) = regex::Regex::new(regex_str) {
-                    *input = regex.replace(input, replacement_str).to_string();
-                    Some(Value::Bool(true))
-                } else {
-                    exit_err!("Invalid regular expression in sub function");
-                }
-            }
-            _ => {
-                exit_err!("Invalid usage of sub function");
-            }
-        }
+    /// The smallest element of an array under the usual comparison rules;
+    /// an empty array gives the uninitialized value.
+    pub fn array_min(&self) -> Option<Value> {
+        self.array_extreme(Ordering::Less)
     }
 
-    pub fn concatenate(&self, other: &Value) -> Option<Value> {
-        match (self, other) {
-            (Value::StringLiteral(a), Value::StringLiteral(b)) => {
-                Some(Value::StringLiteral(a.clone() + b))
-            }
-            _ => None,
-        }
+    /// The largest element of an array, like `array_min`.
+    pub fn array_max(&self) -> Option<Value> {
+        self.array_extreme(Ordering::Greater)
     }
 
-    pub fn length(&self) -> Option<Value> {
+    fn array_extreme(&self, wanted: Ordering) -> Option<Value> {
         match self {
-            Value::StringLiteral(s) => Some(Value::Number(s.len() as i64)),
-            Value::ArrayLiteral(map) => Some(Value::Number(map.len() as i64)),
+            Value::ArrayLiteral(map) => Some(
+                map.values()
+                    .map(|value| value.as_ref())
+                    .reduce(|best, value| if value.compare(best) == wanted { value } else { best })
+                    .cloned()
+                    .unwrap_or(Value::Uninitialized),
+            ),
+            // A name never used before is an empty array here.
+            Value::Uninitialized => Some(Value::Uninitialized),
             _ => None,
         }
     }
 
-    pub fn substring(&self, start: i32, length: i32) -> Option<Value> {
-        match self {
-            Value::StringLiteral(s) => {
-                let start = start as usize;
-                let end = (start + length as usize).min(s.len());
-                Some(Value::StringLiteral(s[start..end].to_string()))
+    pub fn commafy(&self) -> Option<Value> {
+        // Floats are written in full rather than through `%.6g`, which would
+        // turn large ones into exponent form.
+        let rendered = match self {
+            Value::StringLiteral(s) | Value::StrNum(s) => {
+                return Value::Float(string_to_number(s)).commafy();
             }
-            _ => None,
+            Value::Float(f) if f.is_finite() => f.to_string(),
+            scalar if scalar.is_scalar() => scalar.to_string(),
+            _ => return None,
+        };
+
+        let (sign, unsigned) = match rendered.strip_prefix('-') {
+            Some(unsigned) => ("-", unsigned),
+            None => ("", rendered.as_str()),
+        };
+        let (integer_part, fraction) = match unsigned.split_once('.') {
+            Some((integer_part, fraction)) => (integer_part, Some(fraction)),
+            None => (unsigned, None),
+        };
+
+        if !integer_part.chars().all(|ch| ch.is_ascii_digit()) {
+            return Some(Value::StringLiteral(rendered));
         }
-    }
 
-    pub fn index_of(&self, target: &Value) -> Option<Value> {
-        match (self, target) {
-            (Value::StringLiteral(source), Value::StringLiteral(pattern)) => {
-                if let Some(position) = source.find(pattern) {
-                    Some(Value::Number(position as i64 + 1))
-                } else {
-                    Some(Value::Number(0))
-                }
+        let mut grouped = String::new();
+        for (i, digit) in integer_part.chars().enumerate() {
+            if i > 0 && (integer_part.len() - i) % 3 == 0 {
+                grouped.push(',');
             }
-            _ => None,
+            grouped.push(digit);
         }
-    }
 
-    pub fn join(&self, separator: &Value, array: &Value) -> Option<Value> {
-        match (self, separator, array) {
-            (Value::StringLiteral(separator_str), _, Value::ArrayLiteral(array_map)) => {
-                let values: Vec<_> = array_map
-                    .values()
-                    .map(|v| v.get_string().unwrap_or_default())
-                    .collect();
-                Some(Value::StringLiteral(values.join(separator_str)))
+        match fraction {
+            Some(fraction) => {
+                Some(Value::StringLiteral(format!("{}{}.{}", sign, grouped, fraction)))
             }
-            _ => None,
+            None => Some(Value::StringLiteral(format!("{}{}", sign, grouped))),
         }
     }
 
-    pub fn to_lower(&self) -> Option<Value> {
-        match self {
-            Value::StringLiteral(s) => Some(Value::StringLiteral(s.to_lowercase())),
-            _ => None,
-        }
-    }
-
-    pub fn to_upper(&self) -> Option<Value> {
-        match self {
-            Value::StringLiteral(s) => Some(Value::StringLiteral(s.to_uppercase())),
-            _ => None,
-        }
+    // The math builtins work in floating point whatever their argument, so
+    // `sqrt(-1)` and `log(-1)` are NaN rather than errors.
+    fn float_function(&self, function: fn(f64) -> f64) -> Option<Value> {
+        self.is_scalar().then(|| Value::Float(function(self.to_number())))
     }
 
     pub fn cosine(&self) -> Option<Value> {
-        match self {
-            Value::Number(n) => Some(Value::Number((((*n as f64) * PI).cos()) as i64)),
-            Value::Float(f) => Some(Value::Float(f.cos())),
-            _ => None,
-        }
+        self.float_function(f64::cos)
     }
 
     pub fn sine(&self) -> Option<Value> {
-        match self {
-            Value::Number(n) => Some(Value::Number((((*n as f64) * PI).sin()) as i64)),
-            Value::Float(f) => Some(Value::Float(f.sin())),
-            _ => None,
-        }
+        self.float_function(f64::sin)
     }
 
-    pub fn tangent(&self) -> Option<Value> {
-        match self {
-            Value::Number(n) => Some(Value::Number((((*n as f64) * PI).tan()) as i64)),
-            Value::Float(f) => Some(Value::Float(f.tan())),
-            _ => None,
-        }
-    }
-
-    pub fn arctangent(&self) -> Option<Value> {
-        match self {
-            Value::Number(n) => Some(Value::Number((((*n as f64) * PI).atan()) as i64)),
-            Value::Float(f) => Some(Value::Float(f.atan())),
-            _ => None,
+    /// `atan2(self, x)`: the angle of the point (x, self), in radians.
+    pub fn arctangent(&self, x: &Value) -> Option<Value> {
+        if !self.is_scalar() || !x.is_scalar() {
+            return None;
         }
+        Some(Value::Float(self.to_number().atan2(x.to_number())))
     }
 
     pub fn exponential(&self) -> Option<Value> {
-        match self {
-            Value::Number(n) => Some(Value::Number(E.powf(*n as f64) as i64)),
-            Value::Float(f) => Some(Value::Float(f64::exp(*f))),
-            _ => None,
-        }
+        self.float_function(f64::exp)
     }
 
     pub fn logarithm(&self) -> Option<Value> {
-        match self {
-            Value::Number(n) => Some(Value::Number((*n as f64).ln() as i64)),
-            Value::Float(f) => Some(Value::Float(f64::ln(*f))),
-            _ => None,
-        }
+        self.float_function(f64::ln)
     }
 
     pub fn square_root(&self) -> Option<Value> {
-        match self {
-            Value::Number(n) if *n >= 0 => Some(Value::Number((*n as f64).sqrt() as i64)),
-            Value::Float(f) if *f >= 0.0 => Some(Value::Float(f64::sqrt(*f))),
-            _ => None,
-        }
+        self.float_function(f64::sqrt)
     }
 
+    /// Truncates toward zero. Strings are coerced through `to_number` first;
+    /// results outside the active integer width saturate at its bounds and
+    /// NaN becomes 0.
     pub fn int(&self) -> Option<Value> {
         match self {
-            Value::Number(n) => Some(Value::Number(*n)),
-            Value::Float(f) => Some(Value::Number(f64::trunc(*f) as i64)),
-            _ => None,
+            Value::Number(_) | Value::BigNumber(_) => Some(self.clone()),
+            Value::ArrayLiteral(_) | Value::Array(_) => None,
+            scalar => {
+                // `as` saturates out-of-range floats and maps NaN to 0.
+                let truncated = scalar.to_number().trunc();
+                if bignum_mode() {
+                    Some(Value::from_wide_integer(truncated as i128))
+                } else {
+                    Some(Value::Number(truncated as i64))
+                }
+            }
+        }
+    }
+
+    pub fn slurp(&self) -> Option<Value> {
+        let file_path = match self {
+            Value::StringLiteral(path) | Value::StrNum(path) => path,
+            _ => return None,
+        };
+
+        let contents = std::fs::metadata(file_path).and_then(|metadata| {
+            if metadata.len() > MAX_SLURP_SIZE {
+                Err(std::io::Error::other(format!(
+                    "file exceeds {} bytes",
+                    MAX_SLURP_SIZE
+                )))
+            } else {
+                std::fs::read_to_string(file_path)
+            }
+        });
+
+        match contents {
+            Ok(contents) => Some(Value::StringLiteral(contents)),
+            Err(e) => {
+                set_last_error(&format!("{}: {}", file_path, e));
+                Some(Value::StringLiteral(String::new()))
+            }
         }
     }
 
-    pub fn exit(&self) {
-        if let Value::Number(*n) = self {
-            std::process::exit(n);
+    pub fn exit_code(&self) -> i32 {
+        if self.is_scalar() {
+            self.to_number() as i32
+        } else {
+            0
         }
     }
 }
@@ -668,7 +1039,7 @@ impl Add for Value {
     type Output = Option<Value>;
 
     fn add(self, other: Value) -> Self::Output {
-        self.add(other)
+        Value::add(&self, &other)
     }
 }
 
@@ -696,46 +1067,6 @@ impl Div for Value {
     }
 }
 
-impl BitAnd for Value {
-    type Output = Option<Value>;
-
-    fn bitand(self, other: Value) -> Self::Output {
-        self.bitwise_and(&other)
-    }
-}
-
-impl BitOr for Value {
-    type Output = Option<Value>;
-
-    fn bitor(self, other: Value) -> Self::Output {
-        self.bitwise_or(&other)
-    }
-}
-
-impl BitXor for Value {
-    type Output = Option<Value>;
-
-    fn bitxor(self, other: Value) -> Self::Output {
-        self.bitwise_xor(&other)
-    }
-}
-
-impl Shl<Value> for Value {
-    type Output = Option<Value>;
-
-    fn shl(self, other: Value) -> Self::Output {
-        self.shift_left(&other)
-    }
-}
-
-impl Shr<Value> for Value {
-    type Output = Option<Value>;
-
-    fn shr(self, other: Value) -> Self::Output {
-        self.shift_right(&other)
-    }
-}
-
 impl Not for Value {
     type Output = Self;
 
@@ -758,7 +1089,11 @@ impl Neg for Value {
 
     fn neg(self) -> Self::Output {
         match self {
-            Value::Number(n) => Value::Number(-n),
+            Value::Number(n) => match n.checked_neg() {
+                Some(n) => Value::Number(n),
+                None => Value::from_wide_integer(-(n as i128)),
+            },
+            Value::BigNumber(n) => Value::BigNumber(-n),
             Value::Float(f) => Value::Float(-f),
             _ => {
                 panic!(
@@ -770,6 +1105,23 @@ impl Neg for Value {
     }
 }
 
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::BigNumber(n) => write!(f, "{}", n),
+            Value::Float(n) if n.fract() == 0.0 && n.abs() < 1e16 => write!(f, "{}", *n as i64),
+            Value::Float(n) => write!(f, "{}", format(DEFAULT_NUMBER_FORMAT, &[Value::Float(*n)])),
+            Value::Bool(b) => write!(f, "{}", *b as i64),
+            Value::Uninitialized => Ok(()),
+            Value::StringLiteral(s) | Value::StrNum(s) | Value::RegexPattern(s) => {
+                write!(f, "{}", s)
+            }
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -778,13 +1130,102 @@ impl PartialOrd for Value {
 
 impl Ord for Value {
     fn cmp(&self, other: &Self) -> Ordering {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => a.cmp(b),
-            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
-            (Value::StringLiteral(a), Value::StringLiteral(b)) => a.cmp(b),
-            _ => Ordering::Equal,
-        }
+        self.compare(other)
     }
 }
 
 impl Eq for Value {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn substr(source: &str, start: f64, length: Option<f64>) -> Value {
+        Value::StringLiteral(source.to_string()).substring(start, length).unwrap()
+    }
+
+    fn string(text: &str) -> Value {
+        Value::StringLiteral(text.to_string())
+    }
+
+    #[test]
+    fn substring_past_the_end_is_empty() {
+        assert_eq!(substr("abc", 10.0, Some(2.0)), string(""));
+        assert_eq!(substr("abc", 4.0, None), string(""));
+    }
+
+    #[test]
+    fn substring_clamps_start_to_one() {
+        assert_eq!(substr("abc", 0.0, None), string("abc"));
+        assert_eq!(substr("abc", -1.0, Some(3.0)), string("a"));
+    }
+
+    #[test]
+    fn substring_length_past_the_end_stops_at_the_end() {
+        assert_eq!(substr("abc", 2.0, Some(10.0)), string("bc"));
+    }
+
+    #[test]
+    fn commafy_groups_integer_digits() {
+        assert_eq!(Value::Number(1234567).commafy(), Some(string("1,234,567")));
+        assert_eq!(Value::Float(-1234.56).commafy(), Some(string("-1,234.56")));
+        assert_eq!(Value::Float(9876543.5).commafy(), Some(string("9,876,543.5")));
+        assert_eq!(Value::Number(-999).commafy(), Some(string("-999")));
+    }
+
+    #[test]
+    fn reverse_reverses_characters() {
+        assert_eq!(string("abc").reverse(), Some(string("cba")));
+        assert_eq!(string("héllo").reverse(), Some(string("olléh")));
+        assert_eq!(Value::Number(123).reverse(), Some(string("321")));
+    }
+
+    #[test]
+    fn strip_removes_a_set_of_characters_or_whitespace() {
+        assert_eq!(string("xxhixx").strip(Some(&string("x"))), Some(string("hi")));
+        assert_eq!(string(" \thi \n").strip(None), Some(string("hi")));
+        assert_eq!(Value::Number(1001).strip(Some(&Value::Number(1))), Some(string("00")));
+    }
+
+    #[test]
+    fn split_fields_reads_the_separator_like_fs() {
+        assert_eq!(split_fields("  a  b ", " "), ["a", "b"]);
+        assert_eq!(split_fields(",a,", ","), ["", "a", ""]);
+        assert_eq!(split_fields("a.b", "."), ["a", "b"]);
+        assert_eq!(split_fields("a,b:c", "[,:]"), ["a", "b", "c"]);
+        assert_eq!(split_fields("ab", ""), ["a", "b"]);
+    }
+
+    #[test]
+    fn raw_bytes_round_trip_through_characters() {
+        assert_eq!(raw_byte(b'A'), 'A');
+        assert_eq!(raw_byte_value(raw_byte(0xc8)), Some(0xc8));
+        assert_eq!(raw_byte_value('\u{c8}'), None);
+    }
+
+    #[test]
+    fn invalid_utf8_becomes_raw_bytes() {
+        assert_eq!(string_from_bytes("é".as_bytes()), "é");
+        assert_eq!(string_from_bytes(b"a\xc3"), format!("a{}", raw_byte(0xc3)));
+    }
+
+    #[test]
+    fn substitute_expands_ampersands() {
+        let regex = Regex::new("o").unwrap();
+        assert_eq!(substitute("foo", &regex, "<&>", true), ("f<o><o>".to_string(), 2));
+        assert_eq!(substitute("foo", &regex, "\\&", false), ("f&o".to_string(), 1));
+        assert_eq!(substitute("foo", &regex, "\\\\", false), ("f\\o".to_string(), 1));
+    }
+
+    #[test]
+    fn a_pattern_is_compiled_once() {
+        let compilations = || REGEX_COMPILATIONS.with(|count| count.get());
+        let before = compilations();
+        for record in ["foo", "bar", "food"] {
+            get_or_compile("^fo+").unwrap().is_match(record);
+        }
+        assert_eq!(compilations(), before + 1);
+        get_or_compile("ba?r").unwrap();
+        assert_eq!(compilations(), before + 2);
+    }
+}
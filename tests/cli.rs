@@ -0,0 +1,834 @@
+//! Runs the `brawk` binary on small programs and checks what it prints.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn brawk(arguments: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_brawk"))
+        .args(arguments)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start brawk");
+    // A program that never reads its input may exit before it is written.
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    child.wait_with_output().expect("failed to wait for brawk")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// Runs `program` over `input` and returns its standard output, failing the
+/// test if it exits unsuccessfully.
+fn run_with_input(program: &str, input: &str) -> String {
+    let output = brawk(&[program], input);
+    assert!(
+        output.status.success(),
+        "`{}` failed: {}",
+        program,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    stdout(&output)
+}
+
+fn run(program: &str) -> String {
+    run_with_input(program, "")
+}
+
+#[test]
+fn prints_arithmetic() {
+    assert_eq!(run("BEGIN { print 1+2 }"), "3\n");
+}
+
+#[test]
+fn printf_formats_comparisons_as_integers() {
+    assert_eq!(run(r#"BEGIN { printf "%d\n", (3 < 1) }"#), "0\n");
+    assert_eq!(run(r#"BEGIN { printf "%d\n", (2 > 1) }"#), "1\n");
+}
+
+#[test]
+fn substr_clamps_out_of_range_positions() {
+    assert_eq!(run(r#"BEGIN { print "[" substr("abc", 10, 2) "]" }"#), "[]\n");
+    assert_eq!(run(r#"BEGIN { print substr("abc", 0) }"#), "abc\n");
+    assert_eq!(run(r#"BEGIN { print substr("abc", 2, 10) }"#), "bc\n");
+}
+
+#[test]
+fn sprintf_c_prints_characters() {
+    assert_eq!(run(r#"BEGIN { print sprintf("%c", 65) }"#), "A\n");
+    assert_eq!(run(r#"BEGIN { print sprintf("%c", "xyz") }"#), "x\n");
+    assert_eq!(run(r#"BEGIN { print sprintf("%c", 955) }"#), "λ\n");
+}
+
+#[test]
+fn getline_in_a_rule_counts_each_record_once() {
+    assert_eq!(run_with_input("{ getline } END { print NR }", "1\n2\n3\n4\n5\n"), "5\n");
+    assert_eq!(run_with_input("{ getline; print $0, NR }", "a\nb\nc\nd\n"), "b 2\nd 4\n");
+}
+
+/// Runs brawk with command-line `arguments` and returns its exit status,
+/// standard output and standard error.
+fn run_arguments(arguments: &[&str], input: &str) -> (i32, String, String) {
+    let output = brawk(arguments, input);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    (output.status.code().unwrap_or(-1), stdout(&output), stderr)
+}
+
+#[test]
+fn newlines_separate_statements() {
+    assert_eq!(run("BEGIN {\n x = 1\n y = 2\n print x + y\n}"), "3\n");
+}
+
+#[test]
+fn assigning_a_field_rebuilds_the_record_with_ofs() {
+    assert_eq!(run_with_input(r#"BEGIN { OFS = "-" } { $2 = "x"; print }"#, "a b c\n"), "a-x-c\n");
+    assert_eq!(run_with_input(r#"{ $5 = "e"; print; print NF }"#, "a b\n"), "a b   e\n5\n");
+}
+
+#[test]
+fn reading_a_field_keeps_the_record_as_read() {
+    let program = r#"{ x = $1; print "[" $0 "]"; $1 = $1; print "[" $0 "]" }"#;
+    assert_eq!(run_with_input(program, " a   b  \n"), "[ a   b  ]\n[a b]\n");
+}
+
+#[test]
+fn a_rebuilt_record_keeps_the_ofs_of_the_assignment() {
+    let program = r#"{ OFS = "-"; $1 = $1; OFS = ":"; print; print $1, $2 }"#;
+    assert_eq!(run_with_input(program, "a b c\n"), "a-b-c\na:b\n");
+    let program = r#"{ OFS = "-"; $1 = $1; x = $0; OFS = ":"; print x, $0 }"#;
+    assert_eq!(run_with_input(program, "a b\n"), "a-b:a-b\n");
+}
+
+#[test]
+fn ternary_expressions_nest_to_the_right() {
+    assert_eq!(run(r#"BEGIN { x = 5; print (x > 3 ? "big" : x > 1 ? "mid" : "small") }"#), "big\n");
+}
+
+#[test]
+fn nf_counts_the_fields_of_the_current_record() {
+    assert_eq!(run_with_input("{ print NF }", "a b  c\n\n"), "3\n0\n");
+}
+
+#[test]
+fn assigning_nf_truncates_or_extends_the_record() {
+    assert_eq!(run_with_input("{ NF = 2; print; print NF }", "a b c d\n"), "a b\n2\n");
+    assert_eq!(run_with_input(r#"{ OFS = "-"; NF += 2; print }"#, "a b\n"), "a-b--\n");
+}
+
+#[test]
+fn comparisons_chain_left_to_right() {
+    assert_eq!(run("BEGIN { print 1 < 2 < 3, 3 == 3 == 1 }"), "1 1\n");
+}
+
+#[test]
+fn user_functions_recurse_and_return() {
+    let program = "function fact(n) { if (n <= 1) return 1; return n * fact(n - 1) }
+                   BEGIN { print fact(10) }";
+    assert_eq!(run(program), "3628800\n");
+}
+
+#[test]
+fn runaway_recursion_is_an_error() {
+    let (status, _, stderr) =
+        run_arguments(&["function f(n) { return f(n + 1) } BEGIN { f(1) }"], "");
+    assert_eq!(status, 1);
+    assert!(stderr.contains("call stack exhausted"), "{}", stderr);
+}
+
+#[test]
+fn program_can_be_read_from_standard_input() {
+    let (status, output, _) = run_arguments(&["-f", "-"], "BEGIN { print \"hi\" }");
+    assert_eq!((status, output.as_str()), (0, "hi\n"));
+}
+
+#[test]
+fn case_conversion_accepts_numbers_and_unicode() {
+    assert_eq!(
+        run(r#"BEGIN { print toupper(12), toupper("straße"), tolower("ÀB") }"#),
+        "12 STRASSE àb\n"
+    );
+}
+
+#[test]
+fn juxtaposition_concatenates() {
+    assert_eq!(run(r#"BEGIN { x = "a"; print x "b" 1 + 2 }"#), "ab3\n");
+    assert_eq!(run(r#"BEGIN { print (1 " " 2 < 3) }"#), "1\n");
+}
+
+#[test]
+fn assignment_patterns_select_on_the_assigned_value() {
+    assert_eq!(run_with_input("x = $1 { print x }", "0\n5\n"), "5\n");
+}
+
+#[test]
+fn printf_renders_negative_numbers_as_unsigned() {
+    assert_eq!(
+        run(r#"BEGIN { printf "%x|%8o|%#x\n", -1, 8, 255 }"#),
+        "ffffffffffffffff|      10|0xff\n"
+    );
+}
+
+#[test]
+fn bare_length_concatenates_with_what_follows() {
+    assert_eq!(run_with_input(r#"{ print length " chars" }"#, "hello\n"), "5 chars\n");
+}
+
+#[test]
+fn uninitialized_values_are_empty_and_zero() {
+    assert_eq!(run(r#"BEGIN { print "[" x "]", x + 0, length(x) }"#), "[] 0 0\n");
+}
+
+#[test]
+fn referenced_elements_are_uninitialized() {
+    let program = r#"BEGIN { a["x"]; print (a["x"] == 0), (a["x"] == ""), ("x" in a) }"#;
+    assert_eq!(run(program), "1 1 1\n");
+}
+
+#[test]
+fn exit_sets_the_status() {
+    let (status, _, _) = run_arguments(&["BEGIN { exit 3 }"], "");
+    assert_eq!(status, 3);
+}
+
+#[test]
+fn exit_converts_its_status_to_an_integer() {
+    assert_eq!(run_arguments(&["BEGIN { exit 3.7 }"], "").0, 3);
+    assert_eq!(run_arguments(&[r#"BEGIN { exit "abc" }"#], "").0, 0);
+}
+
+#[test]
+fn length_of_numbers_uses_their_string_form() {
+    assert_eq!(run("BEGIN { print length(12345), length(0.5) }"), "5 3\n");
+}
+
+#[test]
+fn constant_folding_can_be_disabled() {
+    let (status, output, _) = run_arguments(&["--no-optimize", "BEGIN { print 2 * 3 + 1 }"], "");
+    assert_eq!((status, output.as_str()), (0, "7\n"));
+}
+
+#[test]
+fn int_truncates_toward_zero() {
+    assert_eq!(run(r#"BEGIN { print int(3.9), int(-3.9), int("12abc") }"#), "3 -3 12\n");
+}
+
+#[test]
+fn comparisons_are_numeric_for_numbers_and_strnums() {
+    assert_eq!(run_with_input("{ print ($1 < $2) }", "10 9\n"), "0\n");
+    assert_eq!(run(r#"BEGIN { print ("10" < "9"), (2 == 2.0), ((1 < 2) == 1) }"#), "1 1 1\n");
+}
+
+#[test]
+fn symtab_reaches_globals() {
+    assert_eq!(run(r#"BEGIN { x = 4; SYMTAB["x"] = 7; print x, SYMTAB["x"] }"#), "7 7\n");
+}
+
+#[test]
+fn command_line_sets_fs_and_variables() {
+    let (_, output, _) = run_arguments(&["-F:", "-v", "x=hi", "{ print x, $2 }"], "a:b\n");
+    assert_eq!(output, "hi b\n");
+}
+
+#[test]
+fn operand_assignments_apply_between_files() {
+    let directory = std::env::temp_dir();
+    let first = directory.join(format!("brawk-operand-1-{}", std::process::id()));
+    let second = directory.join(format!("brawk-operand-2-{}", std::process::id()));
+    std::fs::write(&first, "a\nb\n").unwrap();
+    std::fs::write(&second, "c\n").unwrap();
+    let (first_path, second_path) = (first.to_str().unwrap(), second.to_str().unwrap());
+    let program = "BEGIN { x = 1 } { print $0, x } END { print x }";
+    let (_, output, _) = run_arguments(&[program, first_path, "x=5", second_path], "");
+    assert_eq!(output, "a 1\nb 1\nc 5\n5\n");
+    std::fs::remove_file(first).unwrap();
+    std::fs::remove_file(second).unwrap();
+}
+
+#[test]
+fn argv_and_environ_are_populated() {
+    let (_, output, _) = run_arguments(&["BEGIN { print ARGC, ARGV[1] }", "file"], "");
+    assert_eq!(output, "2 file\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_brawk"))
+        .arg("BEGIN { print ENVIRON[\"BRAWK_TEST\"] }")
+        .env("BRAWK_TEST", "set")
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "set\n");
+}
+
+#[test]
+fn indirect_calls_reach_functions_and_builtins() {
+    let program = r#"function double(n) { return 2 * n }
+                     BEGIN { f = "double"; g = "toupper"; print @f(4), @g("x") }"#;
+    assert_eq!(run(program), "8 X\n");
+}
+
+#[test]
+fn begin_runs_before_input_and_end_after() {
+    let program = r#"END { print "end", NR } { print } BEGIN { print "begin" }"#;
+    assert_eq!(run_with_input(program, "a\n"), "begin\na\nend 1\n");
+}
+
+#[test]
+fn next_skips_the_remaining_rules() {
+    assert_eq!(run_with_input("/skip/ { next } { print }", "a\nskip\nb\n"), "a\nb\n");
+}
+
+#[test]
+fn numbers_print_through_ofmt_and_concatenate_through_convfmt() {
+    let program = r#"BEGIN { OFMT = "%.2f"; CONVFMT = "%.3f"; x = 3.14159; print x; print x "" }"#;
+    assert_eq!(run(program), "3.14\n3.142\n");
+}
+
+#[test]
+fn range_patterns_select_from_start_to_end() {
+    assert_eq!(run_with_input("/b/,/d/", "a\nb\nc\nd\ne\n"), "b\nc\nd\n");
+}
+
+#[test]
+fn parsekv_splits_key_value_pairs() {
+    assert_eq!(
+        run(r#"BEGIN { n = parsekv("a=1;b=2", kv); print n, kv["a"], kv["b"] }"#),
+        "2 1 2\n"
+    );
+}
+
+#[test]
+fn dollar_zero_is_empty_in_begin() {
+    assert_eq!(run(r#"BEGIN { print "[" $0 "]", NF }"#), "[] 0\n");
+}
+
+#[test]
+fn split_takes_a_regex_separator() {
+    assert_eq!(
+        run(r#"BEGIN { n = split("a1b22c", parts, /[0-9]+/); print n, parts[3] }"#),
+        "3 c\n"
+    );
+    assert_eq!(run(r#"BEGIN { print split("", parts) }"#), "0\n");
+}
+
+#[test]
+fn split_handles_space_empty_and_single_character_separators() {
+    let program = r#"BEGIN { n = split(" a b  c ", p, " "); print n, p[1] p[2] p[3] }"#;
+    assert_eq!(run(program), "3 abc\n");
+    let program = r#"BEGIN { p[9]; n = split("abc", p, ""); print n, p[1], p[3], (9 in p) }"#;
+    assert_eq!(run(program), "3 a c 0\n");
+    assert_eq!(run(r#"BEGIN { n = split("a.b.c", p, "."); print n, p[2] }"#), "3 b\n");
+}
+
+#[test]
+fn empty_actions_select_without_printing() {
+    assert_eq!(run_with_input("{}", "a\n"), "");
+}
+
+#[test]
+fn system_returns_the_exit_status() {
+    assert_eq!(run(r#"BEGIN { print system("exit 3") }"#), "3\n");
+}
+
+#[test]
+fn sprintf_returns_the_formatted_string() {
+    assert_eq!(
+        run(r#"BEGIN { s = sprintf("%05.1f|%-3s|", 3.14159, "a"); print s }"#),
+        "003.1|a  |\n"
+    );
+}
+
+#[test]
+fn sprintf_and_printf_format_alike() {
+    let cases = [
+        r#""%d|%5.2f|%-4s|%c", 42, 3.14159, "ab", 65"#,
+        r#""%x %o %e %g", 255, 8, 12345.678, 0.0001"#,
+        r#""%*d|%.*s|%%", 5, 7, 2, "xyz""#,
+        r#""%i %u %s", -3, -1, 1e6"#,
+    ];
+    for case in cases {
+        let printed = run(&format!("BEGIN {{ printf {} }}", case));
+        let formatted = run(&format!(r#"BEGIN {{ printf "%s", sprintf({}) }}"#, case));
+        assert_eq!(printed, formatted, "{}", case);
+    }
+}
+
+#[test]
+fn output_redirection_writes_and_appends_to_files() {
+    let path = std::env::temp_dir().join(format!("brawk-redirect-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let program = format!(
+        r#"BEGIN {{ print "a" > "{0}"; print "b" > "{0}"; close("{0}")
+                   print "c" >> "{0}"; close("{0}")
+                   while ((getline line < "{0}") > 0) print line }}"#,
+        path
+    );
+    assert_eq!(run(&program), "a\nb\nc\n");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn reading_and_writing_a_file_use_separate_handles() {
+    let path = std::env::temp_dir().join(format!("brawk-read-write-{}", std::process::id()));
+    std::fs::write(&path, "1\n2\n").unwrap();
+    let program = format!(
+        r#"BEGIN {{ f = "{}"; getline a < f; print "new" > f; getline b < f
+                   close(f); getline c < f; print a, b, c }}"#,
+        path.display()
+    );
+    assert_eq!(run(&program), "1 2 new\n");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn print_can_pipe_to_a_command() {
+    assert_eq!(
+        run(r#"BEGIN { print "b\na" | "sort"; close("sort"); print "done" }"#),
+        "a\nb\ndone\n"
+    );
+}
+
+#[test]
+fn a_closed_standard_output_still_flushes_files() {
+    let path = std::env::temp_dir().join(format!("brawk-broken-pipe-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let program = format!(r#"BEGIN {{ print "data" > "{}"; while (1) print "y" }}"#, path);
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("'{}' '{}' | head -1", env!("CARGO_BIN_EXE_brawk"), program))
+        .output()
+        .expect("failed to run sh");
+    assert_eq!(stdout(&output), "y\n");
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "data\n");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn getline_reads_from_commands() {
+    assert_eq!(run(r#"BEGIN { "echo hi" | getline x; print x, NR }"#), "hi 1\n");
+}
+
+#[test]
+fn getline_in_end_returns_zero() {
+    assert_eq!(run_with_input(r#"END { print getline, $0 }"#, "a\n"), "0 a\n");
+}
+
+#[test]
+fn getline_from_the_current_file_has_its_own_handle() {
+    let path = std::env::temp_dir().join(format!("brawk-filename-{}", std::process::id()));
+    std::fs::write(&path, "1\n2\n3\n").unwrap();
+    let path = path.to_str().unwrap();
+    let program = r#"{ print; getline l < FILENAME; print "g:" l }
+                     END { close(FILENAME); while ((getline l < FILENAME) > 0) n++; print n }"#;
+    let output = brawk(&[program, path], "");
+    assert_eq!(stdout(&output), "1\ng:1\n2\ng:2\n3\ng:3\n3\n");
+    assert_eq!(stdout(&brawk(&["{ print; close(FILENAME) }", path], "")), "1\n2\n3\n");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn stream_names_do_not_collide_with_standard_output() {
+    let directory = std::env::temp_dir().join(format!("brawk-stdout-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_brawk"))
+        .arg(r#"BEGIN { print "file" > "STDOUT"; print "terminal" }"#)
+        .current_dir(&directory)
+        .output()
+        .expect("failed to run brawk");
+    assert_eq!(stdout(&output), "terminal\n");
+    assert_eq!(std::fs::read_to_string(directory.join("STDOUT")).unwrap(), "file\n");
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn printf_takes_width_and_precision_from_arguments() {
+    assert_eq!(
+        run(r#"BEGIN { printf "[%*d][%-*d][%.*f]\n", 4, 1, 3, 2, 1, 2.25 }"#),
+        "[   1][2  ][2.2]\n"
+    );
+}
+
+#[test]
+fn amin_and_amax_reduce_arrays() {
+    assert_eq!(run(r#"BEGIN { a[1] = 3; a[2] = 10; a[3] = 7; print amin(a), amax(a) }"#), "3 10\n");
+}
+
+#[test]
+fn match_operators_take_dynamic_regexes() {
+    assert_eq!(run(r#"BEGIN { r = "^a"; print ("abc" ~ r), ("abc" !~ /c$/) }"#), "1 0\n");
+}
+
+#[test]
+fn match_operators_bind_looser_than_concatenation() {
+    let program = r#"BEGIN { x = "foo"; print (x ~ "o" "x"), ("a" "b" ~ "^ab$"), (x !~ "f" "o") }"#;
+    assert_eq!(run(program), "0 1 0\n");
+}
+
+#[test]
+fn a_bare_regex_matches_the_record() {
+    let program = r#"{ if (/foo/) print "match:", $0; x = /foo/ + 0; print x }"#;
+    assert_eq!(run_with_input(program, "food\nbar\n"), "match: food\n1\n0\n");
+}
+
+#[test]
+fn integer_valued_subscripts_share_an_element() {
+    assert_eq!(run(r#"BEGIN { a[1] = "x"; print a["1"], a[0.5 + 0.5] }"#), "x x\n");
+}
+
+#[test]
+fn c_replaces_invalid_code_points() {
+    assert_eq!(run(r#"BEGIN { printf "%c", 55296 }"#), "\u{FFFD}");
+}
+
+#[test]
+fn empty_statements_are_allowed() {
+    assert_eq!(run("BEGIN { x = 1;; ; print x; }"), "1\n");
+}
+
+#[test]
+fn float_literals_take_every_form() {
+    assert_eq!(run("BEGIN { print 1.5, .25, 1e3, 2.5E-1, 1. }"), "1.5 0.25 1000 0.25 1\n");
+}
+
+#[test]
+fn crc32_checksums_bytes() {
+    assert_eq!(run(r#"BEGIN { printf "%x\n", crc32("123456789") }"#), "cbf43926\n");
+}
+
+#[test]
+fn compound_assignments_update_in_place() {
+    let program = "BEGIN { x = 10; x += 2; x -= 1; x *= 3; x /= 11; x %= 2; print x
+                   y = 2; y ^= 3; print y }";
+    assert_eq!(run(program), "1\n8\n");
+}
+
+#[test]
+fn compound_assignments_evaluate_the_subscript_once() {
+    let program = "BEGIN { i = 1; print (a[i++] += 5); print i, a[1], length(a) }";
+    assert_eq!(run(program), "5\n2 5 1\n");
+    assert_eq!(run_with_input("{ i = 1; $(i++) += 5; print; print i }", "1 2 3\n"), "6 2 3\n2\n");
+}
+
+#[test]
+fn rules_can_share_a_line() {
+    assert_eq!(run_with_input(r#"/a/ { print "A" }; /b/ { print "B" }"#, "a\nb\n"), "A\nB\n");
+}
+
+#[test]
+fn errors_name_the_program() {
+    let (status, _, stderr) = run_arguments(&["BEGIN { print 1 / 0 }"], "");
+    assert_eq!(status, 1);
+    assert!(stderr.starts_with("brawk: "), "{}", stderr);
+    let (status, _, stderr) = run_arguments(&["BEGIN { print ( }"], "");
+    assert_eq!(status, 2);
+    assert!(stderr.contains("line 1, column 17"), "{}", stderr);
+}
+
+#[test]
+fn arrays_and_scalars_cannot_be_mixed() {
+    let (status, _, stderr) = run_arguments(&[r#"BEGIN { a[1] = 1; print a + 1 }"#], "");
+    assert_eq!(status, 1);
+    assert!(stderr.contains("array `a` cannot be used as a scalar"), "{}", stderr);
+    let (_, _, stderr) = run_arguments(&[r#"BEGIN { s = 1; s[1] = 2 }"#], "");
+    assert!(stderr.contains("scalar `s` cannot be used as an array"), "{}", stderr);
+}
+
+#[test]
+fn arrays_cannot_be_assigned() {
+    let (status, output, stderr) = run_arguments(&["BEGIN { a[1]; b[2]; a = b; print 1 }"], "");
+    assert_eq!((status, output.as_str()), (1, ""));
+    assert!(stderr.contains("array `b` cannot be used as a scalar"), "{}", stderr);
+}
+
+#[test]
+fn wide_integers_print_exactly() {
+    assert_eq!(run(r#"BEGIN { printf "%d\n", 9007199254740993 }"#), "9007199254740993\n");
+}
+
+#[test]
+fn do_while_runs_its_body_once() {
+    assert_eq!(run("BEGIN { x = 5; do { print x; x += 1 } while (x < 3) }"), "5\n");
+}
+
+#[test]
+fn break_and_continue_leave_loops_early() {
+    let program = "BEGIN { for (i = 0; i < 10; i += 1) {
+                       if (i == 1) continue; if (i == 3) break; print i } }";
+    assert_eq!(run(program), "0\n2\n");
+}
+
+#[test]
+fn v_rejects_array_elements() {
+    let (status, _, stderr) = run_arguments(&["-v", "a[1]=2", "BEGIN { }"], "");
+    assert_eq!(status, 1);
+    assert!(stderr.contains("array elements cannot be assigned with -v"), "{}", stderr);
+}
+
+#[test]
+fn integer_overflow_becomes_floating_point() {
+    assert_eq!(
+        run("BEGIN { x = 9223372036854775807; print x + 1, x * 2 }"),
+        "9.22337e+18 1.84467e+19\n"
+    );
+}
+
+#[test]
+fn tab_fs_keeps_empty_edge_fields() {
+    assert_eq!(
+        run_with_input(r#"BEGIN { FS = "\t" } { print NF, "[" $1 "]" $2 "[" $3 "]" }"#, "\ta\t\n"),
+        "3 []a[]\n"
+    );
+}
+
+#[test]
+fn printf_i_is_d_and_unknown_conversions_print_as_written() {
+    assert_eq!(run(r#"BEGIN { printf "%i %q %d 100%", 42, 7 }"#), "42 %q 7 100%");
+}
+
+#[test]
+fn newlines_may_follow_commas_and_logical_operators() {
+    assert_eq!(run("BEGIN { print 1,\n2 }"), "1 2\n");
+    let program = "BEGIN { if (0 ||\n1) print \"or\"; if (1 &&\n\n1) print \"and\" }";
+    assert_eq!(run(program), "or\nand\n");
+    assert_eq!(run("BEGIN { printf \"%s-%s\\n\",\n\"a\",\n\"b\" }"), "a-b\n");
+}
+
+#[test]
+fn comments_run_to_the_end_of_the_line() {
+    let program = "# leading comment\nBEGIN { x = 1 # set x\n  # a whole line\n  print x } # done";
+    assert_eq!(run(program), "1\n");
+    assert_eq!(run_with_input("/a/ # print matches\n", "a\nb\n"), "a\n");
+}
+
+#[test]
+fn exponentiation_binds_tighter_than_unary_minus_and_groups_right() {
+    let program = "BEGIN { print 2^10, 2**10, -2^2, 2^3^2, 2^-1, 2 * 3 ^ 2 }";
+    assert_eq!(run(program), "1024 1024 -4 512 0.5 18\n");
+    assert_eq!(run("BEGIN { x = 3; x **= 2; print x; x ^= 2; print x }"), "9\n81\n");
+}
+
+#[test]
+fn bignum_keeps_large_powers_exact() {
+    let (_, output, _) = run_arguments(&["--bignum", "BEGIN { print 2 ** 100, 2^100 }"], "");
+    let exact = "1267650600228229401496703205376";
+    assert_eq!(output, format!("{} {}\n", exact, exact));
+    assert_eq!(run("BEGIN { print 2^100 }"), "1.26765e+30\n");
+}
+
+#[test]
+fn increments_and_decrements_update_variables_elements_and_fields() {
+    assert_eq!(run("BEGIN { for (i = 0; i < 3; i++) print i }"), "0\n1\n2\n");
+    let program = r#"BEGIN { x = "abc"; print x++, x; print ++y, y--, y, --z }"#;
+    assert_eq!(run(program), "0 1\n1 1 0 -1\n");
+    assert_eq!(run(r#"BEGIN { a["k"]++; ++a["k"]; print a["k"] }"#), "2\n");
+    assert_eq!(run_with_input("{ $2++; print }", "a 5 c\n"), "a 6 c\n");
+}
+
+#[test]
+fn increments_evaluate_the_subscript_once() {
+    let program = "BEGIN { i = 1; print a[i++]++, ++a[i--]; print i, a[1], a[2], length(a) }";
+    assert_eq!(run(program), "0 1\n1 1 1 2\n");
+    let program = "{ i = 2; print $(i++)++, --$(i--); print; print i }";
+    assert_eq!(run_with_input(program, "5 6 7\n"), "6 6\n5 7 6\n2\n");
+}
+
+#[test]
+fn while_loops_can_count_with_increments() {
+    assert_eq!(run_with_input("{ n = 0; while (n < NF) n++; print n }", "a b c\n"), "3\n");
+}
+
+#[test]
+fn for_in_visits_every_element() {
+    assert_eq!(run("BEGIN { a[1] = 2; a[2] = 5; for (k in a) s += a[k]; print s }"), "7\n");
+    assert_eq!(run(r#"BEGIN { for (k in missing) print "never"; print "done" }"#), "done\n");
+    let program = "BEGIN { a[1]; a[2]; for (k in a) { a[k + 10] = 1; n++ }; print n, length(a) }";
+    assert_eq!(run(program), "2 4\n");
+}
+
+#[test]
+fn for_in_supports_break_continue_and_return() {
+    let program = "BEGIN { a[1]; a[2]; a[3]; for (k in a) { if (k == 2) continue; n++ }; print n
+                   for (i = 0; i < 2; i++) for (k in a) break; print i }";
+    assert_eq!(run(program), "2\n2\n");
+    let program = r#"function find(arr, x,   k) { for (k in arr) if (arr[k] == x) return k }
+                     BEGIN { a["p"] = 1; a["q"] = 2; print find(a, 2) find(a, 2) }"#;
+    assert_eq!(run(program), "qq\n");
+}
+
+#[test]
+fn in_tests_membership_without_creating_elements() {
+    let program = "BEGIN { a[1] = 1; if (1 in a) print \"yes\"; print (2 in a), length(a) }";
+    assert_eq!(run(program), "yes\n0 1\n");
+    assert_eq!(run(r#"BEGIN { print ("q" in nothing) }"#), "0\n");
+}
+
+#[test]
+fn grouped_subscripts_test_membership_through_subsep() {
+    assert_eq!(run("BEGIN { b[1, 2] = 3; print ((1, 2) in b), ((2, 1) in b) }"), "1 0\n");
+    let program = r#"BEGIN { SUBSEP = ":"; c["x", "y"]; print (("x", "y") in c), ("x:y" in c) }"#;
+    assert_eq!(run(program), "1 1\n");
+}
+
+#[test]
+fn a_parenthesized_print_list_prints_each_expression() {
+    assert_eq!(run(r#"BEGIN { print("a", "b") }"#), "a b\n");
+    assert_eq!(run(r#"BEGIN { print ("a","b") > "/dev/stdout" }"#), "a b\n");
+}
+
+#[test]
+fn delete_removes_elements_and_whole_arrays() {
+    let program = "BEGIN { a[1]; a[2]; a[3]; delete a[2]; print length(a), (2 in a)
+                   delete a; print length(a); b[1, 2]; delete b[1, 2]; print length(b) }";
+    assert_eq!(run(program), "2 0\n0\n0\n");
+    let (status, _, stderr) = run_arguments(&["BEGIN { s = 1; delete s }"], "");
+    assert_eq!(status, 1);
+    assert!(stderr.contains("scalar `s` cannot be used as an array"), "{}", stderr);
+}
+
+#[test]
+fn gzipped_input_is_decompressed() {
+    let path = std::env::temp_dir().join(format!("brawk-gzip-{}", std::process::id()));
+    std::fs::write(&path, "a 1\nb 2\n").unwrap();
+    let status = Command::new("gzip").arg("-f").arg(&path).status().expect("failed to run gzip");
+    assert!(status.success());
+    let compressed = format!("{}.gz", path.display());
+    let output = brawk(&["{ print $2, NR }", &compressed], "");
+    assert_eq!(stdout(&output), "1 1\n2 2\n");
+    std::fs::remove_file(&compressed).unwrap();
+}
+
+#[test]
+fn slurp_reads_a_whole_file() {
+    let path = std::env::temp_dir().join(format!("brawk-slurp-{}", std::process::id()));
+    std::fs::write(&path, "line 1\nline 2\n").unwrap();
+    let program = format!(r#"BEGIN {{ printf "%s", slurp("{}") }}"#, path.display());
+    assert_eq!(run(&program), "line 1\nline 2\n");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn slurp_failures_set_errno() {
+    let program = r#"BEGIN { s = slurp("/nonexistent/brawk"); print "[" s "]", (ERRNO != "") }"#;
+    assert_eq!(run(program), "[] 1\n");
+}
+
+#[test]
+fn strip_trims_characters_from_both_ends() {
+    assert_eq!(run(r#"BEGIN { print "[" strip("xxhixx", "x") "]" }"#), "[hi]\n");
+    assert_eq!(run(r#"BEGIN { print "[" strip("  hi \t") "]", strip(1001, 1) }"#), "[hi] 00\n");
+}
+
+#[test]
+fn commafy_adds_thousands_separators() {
+    let program = r#"BEGIN { print commafy(1234567), commafy(-1234.56), commafy("9876543.21") }"#;
+    assert_eq!(run(program), "1,234,567 -1,234.56 9,876,543.21\n");
+}
+
+#[test]
+fn reverse_reverses_strings_and_arrays() {
+    assert_eq!(run(r#"BEGIN { print reverse("abc"), reverse("héllo") }"#), "cba olléh\n");
+    let program = r#"BEGIN { split("a b c", a); b["x"]; print reverse(a, b), length(b)
+                              print b[1] b[2] b[3], a[1] a[2] a[3] }"#;
+    assert_eq!(run(program), "3 3\ncba abc\n");
+    let (status, _, stderr) = run_arguments(&[r#"BEGIN { split("a b", a); reverse(a) }"#], "");
+    assert_eq!(status, 1);
+    assert!(stderr.contains("second array"), "{}", stderr);
+}
+
+#[test]
+fn math_builtins_work_in_floating_point() {
+    let program = "BEGIN { print exp(0), log(1), sqrt(16), sin(0), cos(0), atan2(0, -1), exp(1) }";
+    assert_eq!(run(program), "1 0 4 0 1 3.14159 2.71828\n");
+    let program = r#"BEGIN { printf "%f %f %g %e\n", log(-1), 1e400, sqrt(-1), -log(0) }"#;
+    assert_eq!(run(program), "nan inf nan inf\n");
+}
+
+#[test]
+fn srand_makes_rand_repeatable() {
+    let program = "BEGIN { srand(1); a = rand(); srand(1); print (a == rand()), (a >= 0 && a < 1)
+                   print srand(5), srand() }";
+    assert_eq!(run(program), "1 1\n1 5\n");
+    assert_eq!(run("BEGIN { print rand() }"), run("BEGIN { print rand() }"));
+}
+
+#[test]
+fn arrays_are_rejected_as_conditions() {
+    let (code, out, err) = run_arguments(&["BEGIN { arr[1] = 1; if (arr) print \"yes\" }"], "");
+    assert_eq!((code, out.as_str()), (1, ""));
+    assert!(err.contains("array `arr` cannot be used as a scalar"), "{}", err);
+}
+
+#[test]
+fn untyped_arguments_become_the_callers_arrays() {
+    let program = r#"function fill(a) { a["k"] = 1 }
+                     function parts(a) { return split("p q r", a) }
+                     function nested(a) { fill(a); return a["k"] }
+                     BEGIN { fill(x); n = parts(y); print x["k"], n, y[3], nested(z), z["k"] }"#;
+    assert_eq!(run(program), "1 3 r 1 1\n");
+}
+
+#[test]
+fn arrays_made_for_locals_are_released_on_return() {
+    let program = r#"function fill(a) { a["k"]++ }
+                     function count(unused,  t) { fill(t); return t["k"] }
+                     BEGIN { print count(), count() }"#;
+    assert_eq!(run(program), "1 1\n");
+}
+
+#[test]
+fn getline_from_a_command_that_cannot_run_returns_minus_one() {
+    let program = r#"BEGIN { r = ("nonexistent-brawk-command" | getline line); print r
+                             print ("echo hi" | getline line), line }"#;
+    assert_eq!(run(program), "-1\n1 hi\n");
+}
+
+#[test]
+fn fs_splits_records_like_split_does() {
+    assert_eq!(stdout(&brawk(&["-F[,:]", "{ print NF, $3 }"], "a,b:c\n")), "3 c\n");
+    assert_eq!(stdout(&brawk(&["-F::", "{ print NF, $2 }"], "a::b\n")), "2 b\n");
+    assert_eq!(stdout(&brawk(&["-F|", "{ print NF, $2 }"], "a|b\n")), "2 b\n");
+    assert_eq!(run_with_input("BEGIN { FS = \"\" } { print NF, $2 }", "abc\n"), "3 b\n");
+}
+
+#[test]
+fn exit_runs_the_end_actions_before_ending() {
+    let program = r#"{ print; exit 3 } END { print "end" }"#;
+    assert_eq!(run_arguments(&[program], "1\n2\n"), (3, "1\nend\n".to_string(), String::new()));
+    let program = r#"function quit() { exit 2 }
+                     BEGIN { quit(); print "no" } END { print "end"; exit }"#;
+    assert_eq!(run_arguments(&[program], ""), (2, "end\n".to_string(), String::new()));
+    let program = r#"BEGIN { exit 4 } END { exit 5; print "no" }"#;
+    assert_eq!(run_arguments(&[program], ""), (5, String::new(), String::new()));
+}
+
+#[test]
+fn byte_mode_printf_c_writes_the_raw_byte() {
+    assert_eq!(brawk(&["-b", r#"BEGIN { printf "%c|%3c", 200, 65 }"#], "").stdout, b"\xc8|  A");
+    assert_eq!(brawk(&[r#"BEGIN { printf "%c", 200 }"#], "").stdout, "\u{c8}".as_bytes());
+}
+
+#[test]
+fn byte_mode_substr_works_on_bytes() {
+    let program = r#"BEGIN { printf "%s|", substr("héllo", 2, 1)
+                             s = substr("héllo", 2, 1) substr("héllo", 3, 1)
+                             print s, length(s) }"#;
+    assert_eq!(brawk(&["-b", program], "").stdout, b"\xc3|\xc3\xa9 2\n");
+}
+
+#[test]
+fn sub_and_gsub_replace_in_place_and_count() {
+    let program = r#"{ n = gsub(/o/, "[&]"); print n, $0; print sub(/l+/, "L"), $0, NF
+                      s = "aaa"; print gsub(/a/, "\\&", s), s
+                      t = "abc"; gsub(/x*/, "-", t); print t }"#;
+    assert_eq!(
+        run_with_input(program, "hello world\n"),
+        "2 hell[o] w[o]rld\n1 heL[o] w[o]rld 2\n3 &&&\n-a-b-c-\n"
+    );
+    let program = r#"{ sub(/b/, "X", $2); print; sub(/z/, "X", $5); print NF }"#;
+    assert_eq!(run_with_input(program, "a b c\n"), "a X c\n3\n");
+}
+
+#[test]
+fn match_sets_rstart_and_rlength() {
+    let program = r#"BEGIN { print match("foobar", /o+b/), RSTART, RLENGTH
+                             print match("abc", "z"), RSTART, RLENGTH }"#;
+    assert_eq!(run(program), "2 2 3\n0 0 -1\n");
+}